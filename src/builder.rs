@@ -1,29 +1,470 @@
-use std::io::{ Result, Read };
-use std::fs::File;
+use std::collections::HashMap;
+use std::io::{ Result, Error, ErrorKind };
+use std::fs::{ self, File };
+use std::path::{ Component, Path, PathBuf };
 
 use crate::tokenizer::Tokenizer;
 use crate::macro_def::{ Macros };
+use crate::io_helpers::file_to_string;
 
-pub fn build_macros(tokenizer: &Tokenizer, macro_files: Vec<String>) -> Result<Macros> {
-    let mut macros = Macros::new();
+/// Marks a line of a macro manifest as a comment
+const MANIFEST_COMMENT: &str = "#";
+
+/// The line separating macro definitions from the input in combined mode
+const COMBINED_SENTINEL: &str = "---";
+
+/// Starts an optional first line of a macrofile adding to the tokenizer used for that file,
+/// as in `#tokenizer singletons=«» separators=;`. Inputs are still read with the main tokenizer.
+const TOKENIZER_DIRECTIVE: &str = "#tokenizer";
+const SINGLETONS_SETTING: &str = "singletons=";
+const SEPARATORS_SETTING: &str = "separators=";
+
+/// The extension of the macrofiles read from a directory
+const MACROFILE_EXTENSION: &str = "slang";
+
+/// Reads the macros in each of macro_files into macros,
+/// which may already be configured with e.g. a context or features.
+/// A file given more than once (by any path) is only read the first time,
+/// as reading its macros again would conflict with themselves.
+/// Definition sites number the files in the order they are actually read.
+/// Directories and wildcards among macro_files are expanded as by expand_macro_paths.
+pub fn build_macros(tokenizer: &Tokenizer, macro_files: Vec<PathBuf>, mut macros: Macros) -> Result<Macros> {
+    let macro_files = expand_macro_paths(macro_files)?;
+    let mut read_files = Vec::new();
+
+    //Each character a macrofile adds to its tokenizer, whether as a singleton, and the file adding it
+    let mut added_chars: HashMap<char, (bool, PathBuf)> = HashMap::new();
 
     for file_name in macro_files {
-        read_macros(&mut macros, tokenizer, file_name)?;
+        let canonical = file_name.canonicalize()?;
+
+        if read_files.contains(&canonical) {
+            eprintln!("Note: skipping `{}`, which was already read", file_name.display());
+            continue;
+        }
+
+        for (c, singleton) in read_macros(&mut macros, tokenizer, &file_name)? {
+            match added_chars.get(&c) {
+                Some((other_singleton, other_file)) if *other_singleton != singleton => {
+                    return Err(Error::new(ErrorKind::InvalidData, format!(
+                        "`{}` and `{}` disagree on whether {:?} is a singleton or a separator",
+                        other_file.display(), file_name.display(), c)));
+                },
+
+                _ => {
+                    added_chars.insert(c, (singleton, file_name.clone()));
+                }
+            }
+        }
+
+        read_files.push(canonical);
     }
 
     Ok(macros)
 }
 
-fn read_macros(macros: &mut Macros, tokenizer: &Tokenizer, file_name: String) -> Result<()>{
-    let mut file = File::open(file_name)?;
+/// Replaces each directory in paths with the `.slang` files directly inside it,
+/// and each path whose file name has the wildcards `*` or `?` with the files it matches.
+/// Both are sorted by path, so that the order macros are read in is stable.
+pub fn expand_macro_paths(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut expanded = Vec::new();
+
+    for path in paths {
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+
+        let mut matches = if file_name.contains(['*', '?']) {
+            let dir = match path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new(".")
+            };
+
+            list_files(dir, |name| wildcard_matches(file_name, name))?
+        } else if path.is_dir() {
+            list_files(&path, |name| Path::new(name).extension().is_some_and(|extension| extension == MACROFILE_EXTENSION))?
+        } else {
+            expanded.push(path);
+            continue;
+        };
+
+        if matches.is_empty() {
+            eprintln!("Warning: `{}` does not contain any macrofiles", path.display());
+        }
+
+        matches.sort();
+        expanded.extend(matches);
+    }
+
+    Ok(expanded)
+}
+
+/// The files directly inside dir whose names satisfy include
+fn list_files<F>(dir: &Path, include: F) -> Result<Vec<PathBuf>>
+    where
+        F: Fn(&str) -> bool {
+
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_file() && entry.file_name().to_str().is_some_and(&include) {
+            files.push(entry.path());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Whether name matches pattern, in which `*` matches any run of characters and `?` any one
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    //Whether each prefix of name matches the pattern read so far
+    let mut matching = vec![false; name.len() + 1];
+    matching[0] = true;
+
+    for p in pattern {
+        let mut next = vec![false; name.len() + 1];
+
+        for i in 0 ..= name.len() {
+            next[i] = match p {
+                '*' => matching[i] || (i > 0 && next[i - 1]),
+                '?' => i > 0 && matching[i - 1],
+                _ => i > 0 && matching[i - 1] && name[i - 1] == p
+            };
+        }
+
+        matching = next;
+    }
+
+    matching[name.len()]
+}
+
+/// Reads the macrofile paths listed in a manifest, one per line.
+/// Blank lines and lines starting with `#` are ignored,
+/// and relative paths are resolved against the manifest's directory.
+pub fn read_manifest(manifest_file: &Path) -> Result<Vec<PathBuf>> {
+    let manifest_dir = manifest_file.parent().unwrap_or(Path::new(""));
+
+    let contents = file_to_string(File::open(manifest_file)?)?;
+
+    Ok(contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(MANIFEST_COMMENT))
+        .map(|line| resolve_relative(manifest_dir, line))
+        .collect())
+}
+
+/// Resolves a path written in a macro definition file against that file's directory.
+/// Both `/` and `\\` are accepted as separators whatever the platform,
+/// and `.` and `..` components are resolved lexically.
+fn resolve_relative(base_dir: &Path, written: &str) -> PathBuf {
+    if Path::new(written).is_absolute() {
+        return PathBuf::from(written);
+    }
+
+    let mut resolved = base_dir.to_path_buf();
+
+    for part in written.split(['/', '\\']) {
+        match part {
+            "" | "." => {},
+
+            ".." => {
+                match resolved.components().next_back() {
+                    Some(Component::Normal(_)) => {
+                        resolved.pop();
+                    },
+
+                    _ => {
+                        resolved.push("..");
+                    }
+                }
+            },
+
+            _ => {
+                resolved.push(part);
+            }
+        }
+    }
+
+    resolved
+}
+
+/// Splits a combined document at its first sentinel line,
+/// into the macro definitions before it and the input after it
+pub fn split_combined(combined: &str) -> Result<(&str, &str)> {
+    let mut line_start = 0;
+
+    for line in combined.split_terminator('\n') {
+        let line_end = (line_start + line.len() + 1).min(combined.len());
+
+        if line.trim_end_matches('\r') == COMBINED_SENTINEL {
+            return Ok((&combined[ .. line_start], &combined[line_end .. ]));
+        }
+
+        line_start = line_end;
+    }
+
+    Err(Error::new(ErrorKind::InvalidData, format!("Combined input is missing a `{}` line after its macro definitions", COMBINED_SENTINEL)))
+}
+
+/// Reads the macros in a file, returning the characters its header added to the tokenizer
+/// along with whether each is a singleton (rather than a separator)
+fn read_macros(macros: &mut Macros, tokenizer: &Tokenizer, file_name: &Path) -> Result<Vec<(char, bool)>>{
+    let file_data = file_to_string(File::open(file_name)?)?;
+
+    let (added_chars, definitions) = split_tokenizer_header(&file_data)?;
+
+    let mut file_tokenizer = tokenizer.clone();
+    file_tokenizer.extend_singletons(added_chars.iter().filter(|(_, singleton)| *singleton).map(|(c, _)| *c));
+    file_tokenizer.extend_separators(added_chars.iter().filter(|(_, singleton)| !*singleton).map(|(c, _)| *c));
+
+    let tokens = file_tokenizer.tokenize(definitions);
+
+    macros.read_macros(&tokens)?;
+
+    Ok(added_chars)
+}
 
-    let mut file_data = String::new();
+/// Splits the tokenizer directive, if there is one, from the start of a macrofile.
+/// The definitions keep the directive's line break so that their line numbers are unchanged.
+fn split_tokenizer_header(file_data: &str) -> Result<(Vec<(char, bool)>, &str)> {
+    let header = match file_data.strip_prefix(TOKENIZER_DIRECTIVE) {
+        Some(header) => header,
+        None => return Ok((Vec::new(), file_data))
+    };
+
+    let header_end = header.find('\n').unwrap_or(header.len());
+    let mut added_chars = Vec::new();
+
+    for setting in header[ .. header_end].split_whitespace() {
+        if let Some(singletons) = setting.strip_prefix(SINGLETONS_SETTING) {
+            added_chars.extend(singletons.chars().map(|c| (c, true)));
+        } else if let Some(separators) = setting.strip_prefix(SEPARATORS_SETTING) {
+            added_chars.extend(separators.chars().map(|c| (c, false)));
+        } else {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Unknown tokenizer setting `{}`", setting)));
+        }
+    }
+
+    Ok((added_chars, &header[header_end .. ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::macro_def::{ SourceLoc, ConflictPolicy };
+    use crate::io_helpers::{ simplify_output, temp_dir, SharedBuffer };
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_matches("*.slang", "a.slang"));
+        assert!(wildcard_matches("*.slang", ".slang"));
+        assert!(wildcard_matches("a?c*", "abc"));
+        assert!(wildcard_matches("*b*b", "abab"));
+        assert!(!wildcard_matches("*.slang", "a.slang.bak"));
+        assert!(!wildcard_matches("a?c", "ac"));
+    }
 
-    file.read_to_string(&mut file_data)?;
+    #[test]
+    fn reports_unreadable_macrofiles() {
+        let dir = temp_dir("unreadable");
+        let path = dir.join("binary.slang");
 
-    let tokens = tokenizer.tokenize(&file_data);
+        fs::write(&path, b"#define foo : \xff\xfe").unwrap();
 
-    macros.read_macros(&tokens);
+        let result = build_macros(&Tokenizer::default(), vec![path], Macros::new());
+        assert_eq!(result.err().map(|error| error.kind()), Some(ErrorKind::InvalidData));
 
-    Ok(())
-}
\ No newline at end of file
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_macros_from_directories() {
+        let dir = temp_dir("directory");
+        fs::create_dir_all(dir.join("defs/nested")).unwrap();
+
+        fs::write(dir.join("defs/b.slang"), "#define foo : b").unwrap();
+        fs::write(dir.join("defs/a.slang"), "#define bar : a").unwrap();
+        fs::write(dir.join("defs/notes.txt"), "not macros").unwrap();
+        fs::write(dir.join("defs/nested/c.slang"), "#define baz : c").unwrap();
+
+        let expected = vec![dir.join("defs/a.slang"), dir.join("defs/b.slang")];
+
+        assert_eq!(expand_macro_paths(vec![dir.join("defs")]).unwrap(), expected);
+        assert_eq!(expand_macro_paths(vec![dir.join("defs/*.slang")]).unwrap(), expected);
+        assert_eq!(expand_macro_paths(vec![dir.join("defs/notes.t?t")]).unwrap(), vec![dir.join("defs/notes.txt")]);
+
+        let tokenizer = Tokenizer::default();
+        let macros = build_macros(&tokenizer, vec![dir.join("defs")], Macros::new()).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+        macros.expand_tokens(&tokenizer.tokenize("foo bar baz"), &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "b a baz");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_macros_from_manifest() {
+        let dir = temp_dir("manifest");
+        fs::create_dir_all(dir.join("defs")).unwrap();
+
+        fs::write(dir.join("defs/a.slang"), "#define foo : bar").unwrap();
+        fs::write(dir.join("b.slang"), "#define baz : qux").unwrap();
+        fs::write(dir.join("macros.manifest"), "# Project macros\ndefs\\a.slang\n\n  ./defs/../b.slang\n").unwrap();
+
+        let macro_files = read_manifest(&dir.join("macros.manifest")).unwrap();
+
+        assert_eq!(macro_files, vec![dir.join("defs/a.slang"), dir.join("b.slang")]);
+
+        let tokenizer = Tokenizer::default();
+        let macros = build_macros(&tokenizer, macro_files, Macros::new()).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+        macros.expand_tokens(&tokenizer.tokenize("foo baz\n"), &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "bar qux\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_repeated_macro_files() {
+        let dir = temp_dir("repeated");
+
+        fs::write(dir.join("a.slang"), "#define foo : bar").unwrap();
+
+        let macro_files = vec![dir.join("a.slang"), dir.join(".").join("a.slang")];
+        let macros = build_macros(&Tokenizer::default(), macro_files, Macros::new()).unwrap();
+
+        assert_eq!(macros.stats().total, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_definition_sites() {
+        let dir = temp_dir("sites");
+
+        fs::write(dir.join("a.slang"), "#define foo : bar\n\n#define baz $x :\n    qux $x\n").unwrap();
+        fs::write(dir.join("b.slang"), "\n#define quux : corge\n").unwrap();
+
+        let tokenizer = Tokenizer::default();
+        let macros = build_macros(&tokenizer, vec![dir.join("a.slang"), dir.join("b.slang")], Macros::new()).unwrap();
+
+        let site = |pattern| macros.definition_site(&tokenizer.tokenize(pattern)).cloned();
+
+        assert_eq!(site("foo"), Some(SourceLoc { file: 0, line: 1 }));
+        assert_eq!(site("baz $y"), Some(SourceLoc { file: 0, line: 3 }));
+        assert_eq!(site("quux"), Some(SourceLoc { file: 1, line: 2 }));
+        assert_eq!(site("corge"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn macro_files_extend_their_own_tokenizers() {
+        let dir = temp_dir("tokenizers");
+
+        fs::write(dir.join("a.slang"), "#tokenizer singletons=«»\n#define say «$x» : print($x)\n").unwrap();
+        fs::write(dir.join("b.slang"), "#tokenizer singletons=~ separators=;\n#define ~$x;$y : $x $y\n").unwrap();
+
+        let tokenizer = Tokenizer::default();
+        let macro_files = vec![dir.join("a.slang"), dir.join("b.slang")];
+        let macros = build_macros(&tokenizer, macro_files.clone(), Macros::new()).unwrap();
+
+        assert_eq!(macros.definition_site(&tokenizer.tokenize("say « $x »")), Some(&SourceLoc { file: 0, line: 2 }));
+        assert_eq!(macros.definition_site(&tokenizer.tokenize("~ $x $y")), Some(&SourceLoc { file: 1, line: 2 }));
+
+        fs::write(dir.join("c.slang"), "#tokenizer separators=~\n#define tilde : ~\n").unwrap();
+
+        let conflicting = vec![dir.join("b.slang"), dir.join("c.slang")];
+        assert!(build_macros(&tokenizer, conflicting, Macros::new()).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_conflicts_across_files_by_policy() {
+        let dir = temp_dir("conflicts");
+
+        fs::write(dir.join("base.slang"), "#define foo bar : base-bar #define baz : base-baz\n").unwrap();
+        fs::write(dir.join("project.slang"), "#define foo : project\n").unwrap();
+        fs::write(dir.join("mistaken.slang"), "#define qux : one #define qux quux : two\n").unwrap();
+
+        let tokenizer = Tokenizer::default();
+        let macro_files = vec![dir.join("base.slang"), dir.join("project.slang")];
+
+        let expand_with = |policy| {
+            let mut macros = Macros::new();
+            macros.set_conflict_policy(policy);
+
+            let macros = build_macros(&tokenizer, macro_files.clone(), macros)?;
+
+            let buffer = SharedBuffer::default();
+            let mut out_stream = simplify_output(Box::new(buffer.clone()));
+            macros.expand_tokens(&tokenizer.tokenize("foo bar baz\n"), &mut out_stream)?;
+
+            Ok(buffer.contents())
+        };
+
+        let error: Result<String> = expand_with(ConflictPolicy::Error);
+        assert!(error.is_err());
+
+        assert_eq!(expand_with(ConflictPolicy::First).unwrap(), "base-bar base-baz\n");
+        assert_eq!(expand_with(ConflictPolicy::Last).unwrap(), "project bar base-baz\n");
+
+        //Conflicts within one source are errors whatever the policy
+        for &policy in &[ConflictPolicy::First, ConflictPolicy::Last] {
+            let mut macros = Macros::new();
+            macros.set_conflict_policy(policy);
+
+            assert!(build_macros(&tokenizer, vec![dir.join("mistaken.slang")], macros).is_err());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_unix_and_windows_style_paths() {
+        let base_dir = PathBuf::from("project").join("macros");
+        let expected = PathBuf::from("project").join("shared").join("a.slang");
+
+        assert_eq!(resolve_relative(&base_dir, "../shared/a.slang"), expected);
+        assert_eq!(resolve_relative(&base_dir, "..\\shared\\a.slang"), expected);
+        assert_eq!(resolve_relative(&base_dir, "./../shared//a.slang"), expected);
+
+        assert_eq!(resolve_relative(Path::new(""), "../a.slang"), PathBuf::from("..").join("a.slang"));
+    }
+
+    #[test]
+    fn splits_combined_input() {
+        let combined = "#define foo : bar\r\n---\r\nfoo -- baz\n---\n";
+
+        let (macro_section, input_section) = split_combined(combined).unwrap();
+
+        assert_eq!(macro_section, "#define foo : bar\r\n");
+        assert_eq!(input_section, "foo -- baz\n---\n");
+
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize(macro_section)).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+        macros.expand_tokens(&tokenizer.tokenize(input_section), &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "bar -- baz\n---\n");
+    }
+
+    #[test]
+    fn combined_input_requires_sentinel() {
+        assert!(split_combined("#define foo : bar\nfoo\n").is_err());
+    }
+}
@@ -1,5 +1,6 @@
-use std::io::{ Result, Write, Read, stdin };
-use std::fs::{ File };
+use std::io::{ Result, Write, Read, BufWriter, Error, ErrorKind, stdin };
+use std::fs::{ self, File };
+use std::path::{ Path, PathBuf };
 
 pub fn file_to_string(file: File) -> Result<String> {
     let mut file_m = file;
@@ -18,20 +19,221 @@ pub fn stdio_to_string() -> Result<String> {
     Ok(data)
 }
 
+/// Whether both paths exist and lead to the same file
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false
+    }
+}
+
+/// Creates the file to write the output to.
+/// If out_file is also one of in_files creating it would truncate that input,
+/// so this fails unless overwrite_input is set. Then the output is written to
+/// a temporary file instead, whose path is returned so that it can be renamed
+/// over out_file once the output is complete.
+pub fn create_output(out_file: &Path, in_files: &[&Path], overwrite_input: bool) -> Result<(File, Option<PathBuf>)> {
+    if !in_files.iter().any(|in_file| is_same_file(in_file, out_file)) {
+        return Ok((File::create(out_file)?, None));
+    }
+
+    if !overwrite_input {
+        return Err(Error::new(ErrorKind::InvalidInput, format!(
+            "The output `{}` is also an input, use --overwrite-input to replace it",
+            out_file.display())));
+    }
+
+    let mut temp_name = out_file.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".slang-tmp");
+
+    let temp_path = out_file.with_file_name(temp_name);
+
+    Ok((File::create(&temp_path)?, Some(temp_path)))
+}
+
 pub struct SimpleOutput {
-    contents: Box<Write>
+    contents: Box<dyn Write>
 }
 
 impl SimpleOutput {
     pub fn write(&mut self, val: &str) -> Result<()> {
-        self.contents.write(val.as_bytes())?;
+        self.contents.write_all(val.as_bytes())
+    }
 
-        Ok(())
+    /// Writes out anything buffered, which should be done once the output is complete
+    /// since errors writing it when dropped are lost
+    pub fn flush(&mut self) -> Result<()> {
+        self.contents.flush()
+    }
+}
+
+impl Drop for SimpleOutput {
+    /// Flushes anything still buffered, on a best-effort basis
+    fn drop(&mut self) {
+        let _ = self.contents.flush();
     }
 }
 
-pub fn simplify_output(writer: Box<Write>) -> SimpleOutput {
+pub fn simplify_output(writer: Box<dyn Write>) -> SimpleOutput {
     SimpleOutput {
         contents: writer
     }
-}
\ No newline at end of file
+}
+
+/// Buffers writes to writer, collecting up to capacity bytes between writes to it,
+/// or writing straight through if capacity is zero
+pub fn buffer_output(writer: Box<dyn Write>, capacity: usize) -> Box<dyn Write> {
+    if capacity == 0 {
+        writer
+    } else {
+        Box::new(BufWriter::with_capacity(capacity, writer))
+    }
+}
+
+/// A Write implementor for tests whose contents can still be read
+/// after a clone of it has been boxed into a SimpleOutput
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct SharedBuffer {
+    contents: std::rc::Rc<std::cell::RefCell<Vec<u8>>>
+}
+
+#[cfg(test)]
+impl SharedBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.contents.borrow().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.contents.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A Write implementor for tests counting the writes made to it
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CountingWriter {
+    writes: std::rc::Rc<std::cell::Cell<usize>>
+}
+
+#[cfg(test)]
+impl CountingWriter {
+    pub fn writes(&self) -> usize {
+        self.writes.get()
+    }
+}
+
+#[cfg(test)]
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates a fresh directory for a test to write its files into
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("slang-{}-{}", name, std::process::id()));
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_over_input_keeps_source() {
+        let dir = temp_dir("overwrite");
+        let path = dir.join("a.txt");
+
+        fs::write(&path, "source").unwrap();
+
+        assert!(create_output(&path, &[&path], false).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "source");
+
+        let (mut file, temp_path) = create_output(&dir.join(".").join("a.txt"), &[&path], true).unwrap();
+        file.write_all(b"expanded").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "source");
+
+        fs::rename(temp_path.unwrap(), &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "expanded");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Accepts at most one byte per write, like a congested pipe might
+    struct ShortWriter(SharedBuffer);
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.write(&buf[.. buf.len().min(1)])
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_survives_short_writes() {
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(ShortWriter(buffer.clone())));
+
+        out_stream.write("all of it").unwrap();
+
+        assert_eq!(buffer.contents(), "all of it");
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(buffer_output(Box::new(buffer.clone()), 8192));
+
+        out_stream.write("buffered").unwrap();
+        assert_eq!(buffer.contents(), "");
+
+        drop(out_stream);
+        assert_eq!(buffer.contents(), "buffered");
+    }
+
+    #[test]
+    fn buffering_reduces_writes() {
+        let write_tokens = |capacity| {
+            let writer = CountingWriter::default();
+            let mut out_stream = simplify_output(buffer_output(Box::new(writer.clone()), capacity));
+
+            for _ in 0 .. 1000 {
+                out_stream.write("token").unwrap();
+                out_stream.write(" ").unwrap();
+            }
+
+            out_stream.flush().unwrap();
+
+            writer.writes()
+        };
+
+        assert_eq!(write_tokens(0), 2000);
+        assert_eq!(write_tokens(8192), 1);
+    }
+}
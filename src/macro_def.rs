@@ -1,69 +1,3472 @@
-use std::io::{ Result };
+use std::borrow::Cow;
+use std::cell::RefCell;
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::rc::Rc;
+use std::io::{ Result, Error, ErrorKind };
+use std::time::{ Duration, Instant };
 
 use crate::io_helpers::{ SimpleOutput };
-use crate::tokenizer::{ Token };
+use crate::tokenizer::{ Token, Tokenizer, render_tokens };
 
-use crate::trie::{ Trie };
-use crate::trie::hash::{ HashTrie };
+use crate::trie::{ Trie, TrieView, is_prefix_free };
+use crate::trie::hash::{ HashTrie, HashTrieView, Entry };
+use crate::trie::overlapping::OverlappingTrie;
+
+/// Together with one of the keywords, this token begins every definition
+const DEFINITION_START: &str = "#";
+const DEFINE_KEYWORD: &str = "define";
+const FRAGMENT_KEYWORD: &str = "fragment";
+
+/// A definition may be guarded by a feature, as in `#[if feature=NAME] define ...`,
+/// so that it is only read while that feature is enabled
+const GUARD_KEYWORD: &str = "if";
+const FEATURE_SETTING: &str = "feature=";
+
+/// Separates the pattern of a macro definition from its template
+const PATTERN_END: &str = ":";
+
+/// Marks a token as a variable in both patterns and templates
+const VAR_SIGIL: &str = "$";
+
+/// Separates the end of a pattern which must follow a match, but is not consumed by it
+const LOOKAHEAD_START: &str = "?=";
+
+/// Ending a template token just before a newline drops both itself and the line break
+const LINE_CONTINUATION: &str = "\\";
+
+/// Marks a template token as a context variable, whose value is given when expanding.
+/// A default may follow the name, as in `$$VERSION=dev`.
+const CONTEXT_SIGIL: &str = "$$";
+const CONTEXT_DEFAULT_SEPARATOR: &str = "=";
+
+/// Starts a template loop, `$for $xs ( separator ) { body }`, whose separator is optional.
+/// Within the body `$.` is the current token of the capture and `$.n` its position, from 1.
+const FOR_EACH_KEYWORD: &str = "$for";
+const ELEMENT_VAR: &str = ".";
+const ELEMENT_NUMBER_VAR: &str = ".n";
+
+/// Starts a template section written to a named side output rather than the main one,
+/// `$to NAME { body }`. Each rendering of the body is appended to the side output as a line.
+const TO_FILE_KEYWORD: &str = "$to";
+
+/// A debugging aid dumping every variable bound where it appears in a template,
+/// unless the pattern binds a variable of the same name
+const DEBUG_BINDINGS_KEYWORD: &str = "$debug";
+
+/// Marks a token in a pattern as a reference to a named fragment
+const FRAGMENT_SIGIL: &str = "%";
+
+/// Follows the name of a variable in a pattern to make it a sequence variable
+const SEQUENCE_SUFFIX: &str = "...";
+
+/// Separates the name of a variable in a pattern from the token it captures up to
+const UNTIL_SIGIL: &str = "~";
+
+/// Separates alternative patterns that share a single template
+const ALTERNATIVE_SEPARATOR: &str = "|";
+
+/// Marks a token before the pattern as a macro option, such as a transform
+const OPTION_SIGIL: &str = "@";
+
+/// The option requiring the final token of a pattern to be followed by a separator
+const BOUNDARY_OPTION: &str = "boundary";
 
 pub struct Macros {
-    contents: HashTrie<PatternItem, Template>
+    /// Macros are shared with the literal index
+    contents: HashTrie<PatternItem, Rc<Macro>>,
+
+    /// Reusable pieces of patterns, defined with `#fragment NAME : pattern`
+    fragments: HashMap<String, Fragment>,
+
+    /// The number of calls to read_macros so far, which numbers the sources of definitions
+    sources_read: usize,
+
+    /// Skip definitions with syntax errors, with a warning, rather than failing to read the rest
+    recover_syntax_errors: bool,
+
+    conflict_policy: ConflictPolicy,
+
+    render_error_policy: RenderErrorPolicy,
+
+    /// Bounds the backtracking done while matching at each input position
+    max_match_steps: usize,
+
+    /// Report an error where a block pattern finds a different kind of block,
+    /// if no other pattern matches there, rather than leaving it unexpanded
+    strict_block_delimiters: bool,
+
+    /// Match literal tokens and back-references ignoring case.
+    /// Set it before reading macros, as the literal tokens of patterns are lowercased when read.
+    case_insensitive: bool,
+
+    line_directives: Option<LineDirectives>,
+
+    /// Present while every macro is literal only
+    literal_index: Option<LiteralIndex>,
+
+    /// The tokenizer's escape character, which is dropped from the text of templates.
+    /// Set it before reading macros, as templates are unescaped when read.
+    escape_char: Option<char>,
+
+    /// Allow templates to use variables no pattern binds, rendering them as placeholders.
+    /// Set it before reading macros, as templates using unbound variables are rejected when read otherwise.
+    unbound_placeholders: bool,
+
+    /// Written verbatim once before and after the whole expanded output, however many documents it has
+    prologue: Option<String>,
+    epilogue: Option<String>,
+
+    recursion: Option<Recursion>,
+
+    /// The longest each document may take to expand
+    timeout: Option<Duration>,
+
+    /// The features enabling guarded definitions, such as those given by `--feature NAME`.
+    /// Set them before reading macros, as disabled definitions are skipped when read.
+    features: HashSet<String>,
+
+    /// Values for the context variables of templates, such as those given by `--set NAME=VALUE`.
+    /// Set it before reading macros, as their context variables are checked against it.
+    context: HashMap<String, String>,
+
+    /// Rendered templates that have already been expanded recursively, keyed by their depth
+    memo: RefCell<HashMap<(String, usize), Memoized>>,
+
+    #[cfg(test)]
+    memo_hits: Cell<usize>,
+
+    /// While usage is tracked, the number of times each pattern has been expanded
+    usage: Option<RefCell<HashMap<String, usize>>>,
+
+    /// The text written to each named side output by `$to NAME { .. }` so far
+    side_outputs: RefCell<HashMap<String, String>>
+}
+
+/// Makes the output of each expansion be expanded again, to at most max_depth levels
+/// of nesting, with the tokenizer used to split up the rendered templates.
+/// Depths beyond MAX_RECURSION_DEPTH are treated as MAX_RECURSION_DEPTH.
+pub struct Recursion {
+    pub tokenizer: Tokenizer,
+    pub max_depth: usize
+}
+
+/// How many input positions are expanded between checks of the timeout
+const TIMEOUT_CHECK_INTERVAL: usize = 64;
+
+/// Bounds the number of memoized recursive expansions
+const MAX_MEMO_ENTRIES: usize = 4096;
+
+/// Bounds the nesting of recursive expansion whatever its max_depth,
+/// since each level of nesting takes up more of the stack
+pub const MAX_RECURSION_DEPTH: usize = 128;
+
+/// Describes the `#line`-style directives written on their own line before each expansion,
+/// so that tools reading the output can refer back to the original source.
+/// Another is written before the text following an expansion whose output
+/// has a different number of lines than the input it replaced.
+pub struct LineDirectives {
+    /// Where `{line}` is replaced by the line the expansion starts on,
+    /// and `{file}` by the name of the document being expanded, or file_name without one
+    pub format: String,
+    pub file_name: String
+}
+
+impl LineDirectives {
+    fn render(&self, line: usize, document_name: Option<&str>) -> String {
+        self.format
+            .replace("{line}", &line.to_string())
+            .replace("{file}", document_name.unwrap_or(&self.file_name))
+    }
+
+    /// Pushes the directive for line onto output, starting a new line first if output is mid-line
+    fn push<'a>(&self, output: &mut Vec<ExpandedToken<'a>>, line: usize, document_name: Option<&str>) {
+        let mut at_line_start = true;
+
+        for token in output.iter().rev() {
+            if let Some(text) = [&token.suffix, &token.value].iter().find(|text| !text.is_empty()) {
+                at_line_start = text.ends_with('\n');
+                break;
+            }
+        }
+
+        let mut directive = if at_line_start { String::new() } else { "\n".to_string() };
+        directive.push_str(&self.render(line, document_name));
+
+        output.push(ExpandedToken {
+            value: Cow::Owned(directive),
+            suffix: Cow::Borrowed("\n")
+        });
+    }
+}
+
+const DEFAULT_MAX_MATCH_STEPS: usize = 100_000;
+
+/// Controls what happens when a macro's pattern matches but its template cannot be rendered,
+/// as when it uses a variable that only another alternative of the pattern binds
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum RenderErrorPolicy {
+    /// Stop expanding and return the error
+    #[default]
+    Abort,
+
+    /// Write the matched input through unexpanded and print a warning
+    PassthroughWarn
+}
+
+/// Controls what happens when a macro's pattern conflicts with one read from an earlier source,
+/// by being the same as it or a prefix of it, or having it as a prefix.
+/// Conflicts within a single source are always errors.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum ConflictPolicy {
+    /// Fail to read the later definition
+    #[default]
+    Error,
+
+    /// Keep the earlier definition, skipping the later one
+    First,
+
+    /// Replace every earlier definition the later one conflicts with
+    Last
+}
+
+/// A recursive expansion kept for reuse, along with how many times it expanded each pattern,
+/// so that reusing it counts towards usage as expanding it again would
+struct Memoized {
+    expanded: String,
+    usage: Vec<(String, usize)>
+}
+
+/// The value stored in the trie for each macro pattern
+#[derive(Eq, PartialEq, Clone, Debug)]
+struct Macro {
+    /// The pattern the macro was inserted under, as written, for reporting
+    pattern: String,
+
+    site: SourceLoc,
+
+    template: Template,
+
+    /// Applied in order to the rendered template before it is output
+    transforms: Vec<Transform>
+}
+
+/// A summary of a loaded set of macros, counting each alternative pattern separately
+#[derive(Eq, PartialEq, Debug, Default)]
+pub struct MacroSetStats {
+    pub total: usize,
+
+    /// Patterns with any kind of variable, including block variables
+    pub with_vars: usize,
+    pub with_blocks: usize,
+    pub max_pattern_len: usize,
+    pub distinct_first_tokens: usize
+}
+
+/// Written as a line per statistic, for the `--check` summary
+impl fmt::Display for MacroSetStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "macros: {}", self.total)?;
+        writeln!(f, "with variables: {}", self.with_vars)?;
+        writeln!(f, "with blocks: {}", self.with_blocks)?;
+        writeln!(f, "longest pattern: {}", self.max_pattern_len)?;
+        writeln!(f, "distinct first tokens: {}", self.distinct_first_tokens)
+    }
+}
+
+/// Where a macro was defined: the line of its `#define`,
+/// in the source given by the file-th call to read_macros (counting from zero)
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub struct SourceLoc {
+    pub file: usize,
+    pub line: usize
+}
+
+/// A named piece of pattern which is inlined wherever it is referenced.
+/// Its variables are bound in the scope of the macro using it.
+struct Fragment {
+    pattern: Vec<PatternItem>,
+    var_names: Vec<String>
+}
+
+/// The `@name` options written before the pattern of a macro definition
+#[derive(Default)]
+struct MacroOptions {
+    transforms: Vec<Transform>,
+    requires_boundary: bool
 }
 
-#[derive(Hash, Eq, PartialEq, Clone)]
+/// A filter over the whole rendered template of a macro,
+/// requested with e.g. `#define @trim pattern : template`
+#[derive(Eq, PartialEq, Clone, Debug)]
+enum Transform {
+    Trim,
+    Upper,
+    Lower,
+    CollapseWhitespace
+}
+
+impl Transform {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "trim" => Some(Transform::Trim),
+            "upper" => Some(Transform::Upper),
+            "lower" => Some(Transform::Lower),
+            "collapse-whitespace" => Some(Transform::CollapseWhitespace),
+            _ => None
+        }
+    }
+
+    fn apply(&self, rendered: String) -> String {
+        match self {
+            Transform::Trim => rendered.trim().to_string(),
+            Transform::Upper => rendered.to_uppercase(),
+            Transform::Lower => rendered.to_lowercase(),
+            Transform::CollapseWhitespace => rendered.split_whitespace().collect::<Vec<_>>().join(" ")
+        }
+    }
+}
+
+/// A token produced by macro expansion.
+/// Tokens passed through from the input borrow from it,
+/// while rendered templates are owned.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ExpandedToken<'a> {
+    pub value: Cow<'a, str>,
+    pub suffix: Cow<'a, str>
+}
+
+impl<'a> From<&Token<'a>> for ExpandedToken<'a> {
+    fn from(token: &Token<'a>) -> Self {
+        ExpandedToken {
+            value: Cow::Borrowed(token.value),
+            suffix: Cow::Borrowed(token.suffix)
+        }
+    }
+}
+
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 enum PatternItem {
-    Var,
+    /// Captures any single token
+    TokenVar,
 
-    Token {
-        value: String
+    /// Matches a token equal to the one captured by the earlier token variable at index,
+    /// written by repeating the variable's name, as in `let $x = $x`
+    MatchTokenVar {
+        index: u8
+    },
+
+    /// Matches a single token with exactly this value.
+    /// If requires_boundary is set the token must also be followed by a separator
+    /// (or the end of the input), so `in` does not match the start of `in(`
+    MatchToken {
+        value: String,
+        requires_boundary: bool
     },
 
+    /// Captures every token between a balanced pair of delimiters
     BlockVar {
         block_delim: BlockDelimiter
+    },
+
+    /// Captures one or more tokens, written `$name...`.
+    /// Blocks within the run are captured whole,
+    /// and the run cannot extend past the end of its enclosing block.
+    /// The longest run letting the rest of the pattern match is taken: the run starts
+    /// as far as its enclosing block allows and gives back one token (or block) at a time
+    /// whenever the rest fails.
+    SequenceVar,
+
+    /// Captures every token up to the first one, outside of any nested block, matching
+    /// the UntilDelimiter that always follows it. Written `$name~delimiter`.
+    /// The delimiter is not consumed, so the rest of the pattern may match it.
+    /// Like FollowedBy, the delimiter is a separate item so that the matcher can find it
+    /// by descending with each token it scans.
+    CaptureUntil,
+
+    UntilDelimiter {
+        value: String
+    },
+
+    /// A reference to a fragment, written `%name`.
+    /// Fragments are inlined before patterns are inserted into the trie.
+    Fragment {
+        name: String
+    },
+
+    /// Marks the start of the lookahead at the end of a pattern, written `?=`.
+    /// The items after it must match the following input, which is left unconsumed,
+    /// though any variables in them are still bound.
+    /// Keeping the items in the trie path (rather than in this item)
+    /// lets the matcher find them by descending, like any other item.
+    FollowedBy
+}
+
+impl fmt::Display for PatternItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternItem::TokenVar => write!(f, "{}", VAR_SIGIL),
+            PatternItem::MatchTokenVar { index } => write!(f, "{}{}", VAR_SIGIL, index),
+            PatternItem::MatchToken { value, .. } => write!(f, "{}", value),
+            PatternItem::BlockVar { block_delim } => write!(f, "{} {} {}", block_delim.open(), VAR_SIGIL, block_delim.close()),
+            PatternItem::SequenceVar => write!(f, "{}{}", VAR_SIGIL, SEQUENCE_SUFFIX),
+            PatternItem::CaptureUntil => write!(f, "{}{}", VAR_SIGIL, UNTIL_SIGIL),
+            PatternItem::UntilDelimiter { value } => write!(f, "{}", value),
+            PatternItem::Fragment { name } => write!(f, "{}{}", FRAGMENT_SIGIL, name),
+            PatternItem::FollowedBy => write!(f, "{}", LOOKAHEAD_START)
+        }
     }
 }
 
-#[derive(Hash, Eq, PartialEq, Clone)]
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 enum BlockDelimiter {
     SquareBracket,
     CurlyBracket,
     Parenthesis
 }
 
+impl BlockDelimiter {
+    fn from_open(value: &str) -> Option<Self> {
+        match value {
+            "[" => Some(BlockDelimiter::SquareBracket),
+            "{" => Some(BlockDelimiter::CurlyBracket),
+            "(" => Some(BlockDelimiter::Parenthesis),
+            _ => None
+        }
+    }
+
+    fn from_close(value: &str) -> Option<Self> {
+        match value {
+            "]" => Some(BlockDelimiter::SquareBracket),
+            "}" => Some(BlockDelimiter::CurlyBracket),
+            ")" => Some(BlockDelimiter::Parenthesis),
+            _ => None
+        }
+    }
+
+    fn open(&self) -> &'static str {
+        match self {
+            BlockDelimiter::SquareBracket => "[",
+            BlockDelimiter::CurlyBracket => "{",
+            BlockDelimiter::Parenthesis => "("
+        }
+    }
+
+    fn close(&self) -> &'static str {
+        match self {
+            BlockDelimiter::SquareBracket => "]",
+            BlockDelimiter::CurlyBracket => "}",
+            BlockDelimiter::Parenthesis => ")"
+        }
+    }
+}
+
 type Template = Vec<TemplateItem>;
 
-#[derive(Hash, Eq, PartialEq, Clone)]
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 enum TemplateItem {
     Text {
         data: String
     },
     Var {
         index: u8
+    },
+
+    /// A variable bound by no pattern, rendered as the placeholder `<?name>` for a later pass to fill
+    Unbound {
+        name: String
+    },
+
+    /// A value looked up in the context of the expansion, written `$$NAME` or `$$NAME=default`
+    ContextVar {
+        name: String,
+        default: Option<String>
+    },
+
+    /// Renders body once per token captured by a variable, with separator between each.
+    /// The body binds two more variables after those of the pattern,
+    /// to the current token and to its position.
+    ForEach {
+        var_index: u8,
+        body: Template,
+        separator: Option<Template>
+    },
+
+    /// Renders body into the side output called name, leaving nothing in the main output
+    ToFile {
+        name: String,
+        body: Template
+    },
+
+    /// Renders each variable, by name, with the tokens bound to it
+    DebugBindings {
+        names: Vec<String>
     }
 }
 
+/// The result of splitting a token slice at the end of a block
+struct BlockParse<'a> {
+    block_tokens: &'a [Token<'a>],
+    remaining: &'a [Token<'a>]
+}
+
+/// A successful match of a macro at the front of the input
+struct Expansion<'m, 'a> {
+    found: &'m Macro,
+    bindings: Vec<&'a [Token<'a>]>,
+    consumed: usize
+}
+
 impl Macros {
     pub fn new() -> Self {
         Macros {
-            contents: HashTrie::new()
+            contents: HashTrie::new(),
+            fragments: HashMap::new(),
+            sources_read: 0,
+            recover_syntax_errors: false,
+            conflict_policy: ConflictPolicy::default(),
+            render_error_policy: RenderErrorPolicy::default(),
+            max_match_steps: DEFAULT_MAX_MATCH_STEPS,
+            strict_block_delimiters: false,
+            case_insensitive: false,
+            line_directives: None,
+            literal_index: Some(LiteralIndex::new()),
+            escape_char: None,
+            unbound_placeholders: false,
+            prologue: None,
+            epilogue: None,
+            recursion: None,
+            timeout: None,
+            features: HashSet::new(),
+            context: HashMap::new(),
+            memo: RefCell::new(HashMap::new()),
+            #[cfg(test)]
+            memo_hits: Cell::new(0),
+            usage: None,
+            side_outputs: RefCell::new(HashMap::new())
         }
     }
 
-    pub fn read_macros(&mut self, tokens: &[Token]) {
-        //TODO implement
+    pub fn set_unbound_placeholders(&mut self, unbound_placeholders: bool) {
+        self.unbound_placeholders = unbound_placeholders;
     }
 
-    fn read_macro(&mut self, tokens: &[Token]) {
-        //TODO implement
+    /// Starts counting expansions of each pattern across every following document,
+    /// or stops and discards the counts
+    pub fn set_usage_tracking(&mut self, track_usage: bool) {
+        self.usage = if track_usage { Some(RefCell::new(HashMap::new())) } else { None };
     }
 
-    pub fn expand_tokens(&self, input: &[Token], out_stream: &mut SimpleOutput) -> Result<()> {
-        let trie_root = self.contents.as_view();
+    /// Reports how many times each pattern has been expanded while usage was tracked,
+    /// one `count<TAB>pattern` line each, most used first
+    pub fn usage_report(&self) -> String {
+        let usage = match &self.usage {
+            Some(usage) => usage.borrow(),
+            None => return String::new()
+        };
 
-        let mut remaining = input;
+        let mut counts: Vec<(&String, &usize)> = usage.iter().collect();
+        counts.sort_by(|(pattern_a, count_a), (pattern_b, count_b)| count_b.cmp(count_a).then(pattern_a.cmp(pattern_b)));
+
+        counts.iter()
+            .map(|(pattern, count)| format!("{}\t{}\n", count, pattern))
+            .collect()
+    }
+
+    /// The number of patterns read, counting each alternative of a definition
+    pub fn pattern_count(&self) -> usize {
+        self.contents.len()
+    }
+
+    /// Describes the settings that affect reading and expanding, one `name: value` line each
+    pub fn describe_settings(&self) -> String {
+        let mut features: Vec<&str> = self.features.iter().map(String::as_str).collect();
+        features.sort();
+
+        let mut context: Vec<String> = self.context.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        context.sort();
+
+        let settings = [
+            ("macros", self.pattern_count().to_string()),
+            ("recursion depth", self.recursion.as_ref().map_or("off".to_string(), |recursion| recursion.max_depth.min(MAX_RECURSION_DEPTH).to_string())),
+            ("timeout", self.timeout.map_or("none".to_string(), |timeout| format!("{}ms", timeout.as_millis()))),
+            ("max match steps", self.max_match_steps.to_string()),
+            ("strict blocks", self.strict_block_delimiters.to_string()),
+            ("ignore case", self.case_insensitive.to_string()),
+            ("recover syntax errors", self.recover_syntax_errors.to_string()),
+            ("unbound placeholders", self.unbound_placeholders.to_string()),
+            ("on render error", match self.render_error_policy { RenderErrorPolicy::Abort => "abort", RenderErrorPolicy::PassthroughWarn => "warn" }.to_string()),
+            ("on conflict", format!("{:?}", self.conflict_policy).to_lowercase()),
+            ("line directives", self.line_directives.as_ref().map_or("off".to_string(), |directives| directives.format.clone())),
+            ("features", features.join(" ")),
+            ("context", context.join(" "))
+        ];
+
+        settings.iter()
+            .map(|(name, value)| format!("{}: {}\n", name, value))
+            .collect()
+    }
+
+    /// Removes and returns the text written to each side output so far
+    pub fn take_side_outputs(&self) -> HashMap<String, String> {
+        self.side_outputs.replace(HashMap::new())
+    }
+
+    /// The total length of the side outputs, which only grows while expanding
+    fn side_outputs_len(&self) -> usize {
+        self.side_outputs.borrow().values().map(String::len).sum()
+    }
+
+    pub fn set_recover_syntax_errors(&mut self, recover_syntax_errors: bool) {
+        self.recover_syntax_errors = recover_syntax_errors;
+    }
+
+    pub fn set_conflict_policy(&mut self, conflict_policy: ConflictPolicy) {
+        self.conflict_policy = conflict_policy;
+    }
+
+    pub fn set_features(&mut self, features: HashSet<String>) {
+        self.features = features;
+    }
+
+    pub fn set_context(&mut self, context: HashMap<String, String>) {
+        self.context = context;
+    }
+
+    pub fn set_escape_char(&mut self, escape_char: Option<char>) {
+        self.escape_char = escape_char;
+    }
+
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_recursion(&mut self, recursion: Option<Recursion>) {
+        self.recursion = recursion;
+        self.memo.borrow_mut().clear();
+    }
+
+    pub fn set_prologue(&mut self, prologue: Option<String>) {
+        self.prologue = prologue;
+    }
+
+    pub fn set_epilogue(&mut self, epilogue: Option<String>) {
+        self.epilogue = epilogue;
+    }
+
+    pub fn prologue(&self) -> Option<&str> {
+        self.prologue.as_deref()
+    }
+
+    pub fn epilogue(&self) -> Option<&str> {
+        self.epilogue.as_deref()
+    }
+
+    pub fn set_line_directives(&mut self, line_directives: Option<LineDirectives>) {
+        self.line_directives = line_directives;
+    }
+
+    pub fn set_max_match_steps(&mut self, max_match_steps: usize) {
+        self.max_match_steps = max_match_steps;
+    }
+
+    pub fn set_strict_block_delimiters(&mut self, strict_block_delimiters: bool) {
+        self.strict_block_delimiters = strict_block_delimiters;
+    }
+
+    pub fn set_render_error_policy(&mut self, policy: RenderErrorPolicy) {
+        self.render_error_policy = policy;
+    }
+
+    /// Reads every `#define pattern : template` and `#fragment NAME : pattern` definition in tokens
+    pub fn read_macros(&mut self, tokens: &[Token]) -> Result<()> {
+        let mut remaining = match tokens {
+            //Leading whitespace is held by a token with an empty value
+            [first, rest @ ..] if first.value.is_empty() => rest,
+            _ => tokens
+        };
+
+        let mut line = 1 + count_lines(&tokens[ .. tokens.len() - remaining.len()]);
+
+        if let Some(token) = remaining.first() {
+            if definition_header(remaining).is_none() {
+                return Err(syntax_error(format!("Expected `#define` or `#fragment` on line {}, column {} but found `{}`", token.line, token.column, token.value)));
+            }
+        }
+
+        let file = self.sources_read;
+        self.sources_read += 1;
+
+        //Each macro definition adds at least one node, so room for one per definition is reserved up front
+        let definition_count = (0 .. remaining.len())
+            .filter(|&index| definition_header(&remaining[index ..]).is_some())
+            .count();
 
-        //TODO implement
+        self.contents.reserve(definition_count);
+
+        while let Some((feature, keyword_index)) = definition_header(remaining) {
+            let definition_end = (keyword_index + 1 .. remaining.len())
+                .find(|&index| definition_header(&remaining[index ..]).is_some())
+                .unwrap_or(remaining.len());
+
+            let definition = &remaining[keyword_index + 1 .. definition_end];
+            let enabled = feature.is_none_or(|feature| self.features.contains(feature));
+
+            //Near misses such as `#defined` may be intended template text, so they only warn
+            if let Some(stray) = find_misspelled_header(definition) {
+                let stray_line = line + count_lines(&remaining[ .. keyword_index + 1 + stray]);
+
+                eprintln!("Warning: `{}{}` on line {} is not a definition, is it misspelled?", DEFINITION_START, definition[stray + 1].value, stray_line);
+            }
+
+            let result = if !enabled {
+                //Skipped without being parsed, so it may use syntax only valid with the feature
+                Ok(())
+            } else if remaining[keyword_index].value == FRAGMENT_KEYWORD {
+                self.read_fragment(definition)
+            } else {
+                self.read_macro(definition, SourceLoc { file, line })
+            };
+
+            //Definitions are delimited before being parsed, so the next one can still be read.
+            //Only syntax errors are recovered from, and a definition is never partly read.
+            match result {
+                Err(error) if self.recover_syntax_errors && error.kind() == ErrorKind::InvalidData => {
+                    eprintln!("Warning: skipping the definition on line {}: {}", line, error);
+                },
+
+                result => result?
+            }
+
+            line += count_lines(&remaining[ .. definition_end]);
+            remaining = &remaining[definition_end .. ];
+        }
+
+        //Conflicting patterns are never inserted, so none can be a prefix of another
+        debug_assert!(is_prefix_free(&self.contents.keys().collect::<Vec<_>>()).is_ok());
+
+        Ok(())
+    }
+
+    /// Reads a single `NAME : pattern` fragment definition.
+    /// References to other fragments are resolved when a macro uses it.
+    fn read_fragment(&mut self, tokens: &[Token]) -> Result<()> {
+        let (name, pattern_tokens) = match tokens {
+            [name, separator, pattern_tokens @ ..] if separator.value == PATTERN_END => (name.value, pattern_tokens),
+            _ => {
+                return Err(syntax_error(format!("Expected `#{} NAME {} pattern`", FRAGMENT_KEYWORD, PATTERN_END)));
+            }
+        };
+
+        if pattern_tokens.is_empty() {
+            return Err(syntax_error(format!("Fragment `{}` has an empty pattern", name)));
+        }
+
+        let mut var_names = Vec::new();
+        let pattern = parse_pattern(pattern_tokens, &mut var_names)?;
+
+        if self.fragments.contains_key(name) {
+            return Err(syntax_error(format!("Fragment `{}` is defined more than once", name)));
+        }
+
+        self.fragments.insert(name.to_string(), Fragment { pattern, var_names });
+
+        Ok(())
+    }
+
+    /// Replaces every fragment reference in pattern with the fragment's items,
+    /// returning the names of the variables of the result in order.
+    /// expanding holds the fragments currently being inlined, to detect cycles.
+    fn inline_fragments(
+        &self,
+        pattern: &[PatternItem],
+        var_names: &[String],
+        expanding: &mut Vec<String>,
+        inlined: &mut Vec<PatternItem>,
+        inlined_var_names: &mut Vec<String>) -> Result<()> {
+
+        let mut var_names = var_names.iter();
+
+        //The index in inlined_var_names of each variable declared so far by pattern
+        let mut inlined_indexes = Vec::new();
+
+        for item in pattern {
+            match item {
+                PatternItem::Fragment { name } => {
+                    if expanding.contains(name) {
+                        return Err(syntax_error(format!("Fragment `{}` refers to itself", name)));
+                    }
+
+                    let fragment = self.fragments.get(name)
+                        .ok_or_else(|| syntax_error(format!("Unknown fragment `{}{}`", FRAGMENT_SIGIL, name)))?;
+
+                    expanding.push(name.clone());
+                    self.inline_fragments(&fragment.pattern, &fragment.var_names, expanding, inlined, inlined_var_names)?;
+                    expanding.pop();
+                },
+
+                PatternItem::MatchToken { .. } | PatternItem::FollowedBy | PatternItem::UntilDelimiter { .. } => {
+                    inlined.push(item.clone());
+                },
+
+                PatternItem::MatchTokenVar { index } => {
+                    let inlined_index = inlined_indexes[*index as usize];
+
+                    inlined.push(PatternItem::MatchTokenVar { index: inlined_index });
+                },
+
+                PatternItem::TokenVar | PatternItem::BlockVar { .. } | PatternItem::SequenceVar | PatternItem::CaptureUntil => {
+                    //Each variable item had its name declared in order by parse_pattern
+                    let name = var_names.next().expect("Variable item without a name");
+
+                    inlined_indexes.push(inlined_var_names.len() as u8);
+                    declare_var(inlined_var_names, name)?;
+                    inlined.push(item.clone());
+                }
+            }
+        }
 
         Ok(())
     }
+
+    /// Reads a single `@option.. pattern | pattern.. : template` definition into the trie,
+    /// inserting a copy of the template under each alternative pattern.
+    /// Every alternative is parsed, and checked for conflicts when they are errors,
+    /// before any is inserted, so that a definition is never partly read.
+    fn read_macro(&mut self, tokens: &[Token], site: SourceLoc) -> Result<()> {
+        let (options, tokens) = parse_options(tokens)?;
+
+        let pattern_end = tokens.iter()
+            .position(|token| token.value == PATTERN_END)
+            .ok_or_else(|| syntax_error(format!("Macro definition is missing `{}`", PATTERN_END)))?;
+
+        let template_tokens = &tokens[pattern_end + 1 .. ];
+        let mut alternatives = Vec::new();
+        let mut bound_names = Vec::new();
+
+        for pattern_tokens in split_top_level(&tokens[ .. pattern_end], ALTERNATIVE_SEPARATOR) {
+            if pattern_tokens.is_empty() {
+                return Err(syntax_error("Macro definition has an empty pattern".to_string()));
+            }
+
+            //Variables are numbered per alternative, so the template is parsed for each
+            let mut var_names = Vec::new();
+            let written_pattern = parse_pattern(pattern_tokens, &mut var_names)?;
+
+            let mut pattern = Vec::new();
+            let mut inlined_var_names = Vec::new();
+            self.inline_fragments(&written_pattern, &var_names, &mut Vec::new(), &mut pattern, &mut inlined_var_names)?;
+            validate_lookahead(&pattern)?;
+
+            if self.case_insensitive {
+                lowercase_literals(&mut pattern);
+            }
+
+            let mut template = parse_template(template_tokens, &inlined_var_names, &self.context)?;
+            bound_names.extend(inlined_var_names);
+
+            if let Some(escape_char) = self.escape_char {
+                template = unescape_template(template, escape_char);
+            }
+
+            let template = coalesce_text(template);
+
+            if uses_debug_bindings(&template) {
+                eprintln!("Note: the template on line {} dumps its bindings with `{}`", site.line, DEBUG_BINDINGS_KEYWORD);
+            }
+
+            if options.requires_boundary {
+                match pattern.last_mut() {
+                    Some(PatternItem::MatchToken { requires_boundary, .. }) => {
+                        *requires_boundary = true;
+                    },
+
+                    _ => {
+                        return Err(syntax_error(format!("`{}{}` requires the pattern to end with a literal token", OPTION_SIGIL, BOUNDARY_OPTION)));
+                    }
+                }
+            }
+
+            let new_macro = Macro {
+                pattern: describe_pattern(&pattern),
+                site,
+                template,
+                transforms: options.transforms.clone()
+            };
+
+            alternatives.push((pattern, new_macro));
+        }
+
+        //A variable bound by only some alternatives fails to render for the others, which the render error policy handles,
+        //but one bound by none of them can only render as a placeholder
+        if !self.unbound_placeholders {
+            let never_bound = alternatives.iter()
+                .flat_map(|(_, new_macro)| unbound_vars(&new_macro.template))
+                .find(|name| !bound_names.iter().any(|bound| bound == name));
+
+            if let Some(name) = never_bound {
+                return Err(syntax_error(format!("Template uses unbound variable `{}{}`", VAR_SIGIL, name)));
+            }
+        }
+
+        //The conflict policy only settles conflicts between sources,
+        //as within one source a conflict is a mistake rather than an override
+        for (index, (pattern, new_macro)) in alternatives.iter().enumerate() {
+            let earlier_alternative = alternatives[ .. index].iter()
+                .find(|(earlier, _)| patterns_conflict(earlier, pattern));
+
+            if let Some((_, existing)) = earlier_alternative {
+                return Err(conflict_error(new_macro, Some(existing)));
+            }
+
+            if !self.is_vacant(pattern) {
+                let existing = self.conflicting_macro(pattern);
+                let same_source = existing.is_none_or(|existing| existing.site.file == new_macro.site.file);
+
+                if same_source || self.conflict_policy == ConflictPolicy::Error {
+                    return Err(conflict_error(new_macro, existing));
+                }
+            }
+        }
+
+        for (pattern, new_macro) in alternatives {
+            if !self.insert_macro(pattern.clone(), new_macro.clone()) {
+                match self.conflict_policy {
+                    //Only reached if the trie has run out of node ids, as conflicts were checked for above
+                    ConflictPolicy::Error => {
+                        return Err(conflict_error(&new_macro, self.conflicting_macro(&pattern)));
+                    },
+
+                    ConflictPolicy::First => {},
+
+                    ConflictPolicy::Last => {
+                        self.replace_conflicting(pattern, new_macro);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a macro could be inserted under pattern without conflicting with those already read
+    fn is_vacant(&mut self, pattern: &[PatternItem]) -> bool {
+        matches!(self.contents.entry(pattern.iter().cloned()), Some(Entry::Vacant(_)))
+    }
+
+    /// The token values a pattern matches, if it is made up only of literal tokens
+    /// the literal index can match
+    fn literal_values<'p>(&self, pattern: &'p [PatternItem]) -> Option<Vec<&'p str>> {
+        //The literal index matches token values exactly
+        if self.case_insensitive {
+            return None;
+        }
+
+        pattern.iter()
+            .map(|item| match item {
+                PatternItem::MatchToken { value, requires_boundary: false } => Some(value.as_str()),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// Inserts a macro into the trie, keeping the literal index up to date,
+    /// unless it conflicts with one already inserted
+    fn insert_macro(&mut self, pattern: Vec<PatternItem>, new_macro: Macro) -> bool {
+        let literal_values = self.literal_values(&pattern);
+
+        //The trie would replace the value of an identical pattern, but that is a conflict too
+        let vacant = match self.contents.entry(pattern.iter().cloned()) {
+            Some(Entry::Vacant(vacant)) => vacant,
+            _ => return false
+        };
+
+        let new_macro = Rc::new(new_macro);
+
+        match (&mut self.literal_index, literal_values) {
+            (Some(literal_index), Some(literal_values)) => {
+                literal_index.insert(&literal_values, Rc::clone(&new_macro));
+            },
+
+            _ => {
+                self.literal_index = None;
+            }
+        }
+
+        vacant.insert(new_macro);
+        true
+    }
+
+    /// Removes the macros whose patterns conflict with pattern,
+    /// then inserts the new macro under it
+    fn replace_conflicting(&mut self, pattern: Vec<PatternItem>, new_macro: Macro) {
+        let conflicting: Vec<Vec<PatternItem>> = self.contents.keys()
+            .filter(|existing| patterns_conflict(existing.iter().cloned(), &pattern))
+            .map(|existing| existing.into_iter().cloned().collect())
+            .collect();
+
+        for existing in conflicting {
+            if let (Some(literal_values), Some(literal_index)) = (self.literal_values(&existing), &mut self.literal_index) {
+                literal_index.remove(&literal_values);
+            }
+
+            self.contents.remove(existing);
+        }
+
+        self.insert_macro(pattern, new_macro);
+    }
+
+    /// Writes every macro read, in the order they were defined, as a line with
+    /// where it was defined and its pattern as written, followed by indented lines with
+    /// the items of its pattern as stored in the trie, its template and any transforms
+    pub fn dump(&self, out_stream: &mut SimpleOutput) -> Result<()> {
+        let mut entries = self.contents.entries();
+        entries.sort_by_key(|(_, found)| (found.site.file, found.site.line));
+
+        for (path, found) in entries {
+            out_stream.write(&format!("source {} line {}: {}\n", found.site.file, found.site.line, found.pattern))?;
+            out_stream.write(&format!("  pattern: {:?}\n", path))?;
+            out_stream.write(&format!("  template: {:?}\n", describe_template(&found.template)))?;
+
+            if !found.transforms.is_empty() {
+                out_stream.write(&format!("  transforms: {:?}\n", found.transforms))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A macro already read whose pattern conflicts with pattern, if any
+    fn conflicting_macro(&self, pattern: &[PatternItem]) -> Option<&Macro> {
+        self.contents.entries()
+            .into_iter()
+            .find(|(existing, _)| patterns_conflict(existing.iter().cloned(), pattern))
+            .map(|(_, found)| &**found)
+    }
+
+    /// Finds where the macro with the given pattern was defined,
+    /// with the pattern written as it would be in a definition
+    pub fn definition_site(&self, pattern: &[Token]) -> Option<&SourceLoc> {
+        let mut var_names = Vec::new();
+        let written_pattern = parse_pattern(pattern, &mut var_names).ok()?;
+
+        let mut inlined = Vec::new();
+        self.inline_fragments(&written_pattern, &var_names, &mut Vec::new(), &mut inlined, &mut Vec::new()).ok()?;
+
+        if self.case_insensitive {
+            lowercase_literals(&mut inlined);
+        }
+
+        self.contents.get(inlined)?
+            .value()
+            .map(|found| &found.site)
+    }
+
+    /// The most tokens any pattern can match (including its lookahead),
+    /// or None if some pattern has a block or sequence variable, which match any number
+    pub fn max_pattern_len(&self) -> Option<usize> {
+        let mut max_len = 0;
+
+        for pattern in self.contents.keys() {
+            for item in &pattern {
+                match item {
+                    PatternItem::BlockVar { .. } | PatternItem::SequenceVar | PatternItem::CaptureUntil => {
+                        return None;
+                    },
+
+                    _ => {}
+                }
+            }
+
+            max_len = max_len.max(pattern.len());
+        }
+
+        Some(max_len)
+    }
+
+    /// Expands a macro matching the very start of input, if there is one,
+    /// returning its rendering and the number of tokens it consumed.
+    /// The suffix of the last consumed token is left to the caller.
+    pub(crate) fn expand_next(&self, input: &[Token]) -> Result<Option<(String, usize)>> {
+        let expansion = match &self.literal_index {
+            Some(literal_index) => literal_index.find(input),
+            None => Matcher::new(input, self.max_match_steps, self.strict_block_delimiters, self.case_insensitive).match_from(&self.contents.as_view(), 0)?
+        };
+
+        match expansion {
+            Some(Expansion { found, bindings, consumed }) => Ok(Some((self.render_macro(found, &bindings)?, consumed))),
+            None => Ok(None)
+        }
+    }
+
+    /// Cheaply estimates the length in bytes of expanding input, without rendering any templates.
+    /// Variables are estimated by the length of their captures, so this is an upper bound
+    /// unless transforms lengthen the output, or recursion, line directives,
+    /// the prologue or the epilogue are in use, which it leaves out.
+    pub fn estimate_output_len(&self, input: &[Token]) -> usize {
+        let trie_root = self.contents.as_view();
+
+        let mut estimate = 0;
+        let mut remaining = input;
+
+        while let Some(token) = remaining.first() {
+            let expansion = match &self.literal_index {
+                Some(literal_index) => literal_index.find(remaining),
+                None => Matcher::new(remaining, self.max_match_steps, false, self.case_insensitive).match_from(&trie_root, 0).unwrap_or(None)
+            };
+
+            match expansion {
+                Some(Expansion { found, bindings, consumed }) => {
+                    estimate += estimate_template(&found.template, &bindings, &self.context);
+                    estimate += remaining[consumed - 1].suffix.len();
+
+                    remaining = &remaining[consumed .. ];
+                },
+
+                None => {
+                    estimate += token.value.len() + token.suffix.len();
+
+                    remaining = &remaining[1 .. ];
+                }
+            }
+        }
+
+        estimate
+    }
+
+    /// True if every pattern is made up only of literal tokens,
+    /// in which case expansion uses the faster literal matcher
+    pub fn is_literal_only(&self) -> bool {
+        self.literal_index.is_some()
+    }
+
+    pub fn expand_tokens(&self, input: &[Token], out_stream: &mut SimpleOutput) -> Result<()> {
+        self.expand_named(input, None, out_stream)
+    }
+
+    fn expand_named(&self, input: &[Token], document_name: Option<&str>, out_stream: &mut SimpleOutput) -> Result<()> {
+        out_stream.write(&self.expand_string(input, document_name)?)
+    }
+
+    /// Expands input into a single string, reserving its estimated length up front
+    fn expand_string(&self, input: &[Token], document_name: Option<&str>) -> Result<String> {
+        let mut expanded = String::with_capacity(self.estimate_output_len(input));
+
+        for token in self.expand_named_to_tokens(input, document_name)? {
+            expanded.push_str(&token.value);
+            expanded.push_str(&token.suffix);
+        }
+
+        Ok(expanded)
+    }
+
+    /// Expands a whole document, bracketing it with the prologue and epilogue if they are set.
+    /// They are written even when the document is empty.
+    pub fn expand_document(&self, input: &[Token], out_stream: &mut SimpleOutput) -> Result<()> {
+        self.expand_documents(&[(None, input)], "", out_stream)
+    }
+
+    /// Expands several documents into one output, writing separator between each of them.
+    /// Each document may be named for use in line directives.
+    /// The prologue and epilogue bracket the whole output, rather than each document.
+    pub fn expand_documents(&self, documents: &[(Option<&str>, &[Token])], separator: &str, out_stream: &mut SimpleOutput) -> Result<()> {
+        if let Some(prologue) = &self.prologue {
+            out_stream.write(prologue)?;
+        }
+
+        for (index, (document_name, input)) in documents.iter().enumerate() {
+            if index > 0 {
+                out_stream.write(separator)?;
+            }
+
+            self.expand_named(input, *document_name, out_stream)?;
+        }
+
+        if let Some(epilogue) = &self.epilogue {
+            out_stream.write(epilogue)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> MacroSetStats {
+        let mut stats = MacroSetStats::default();
+        let mut first_tokens = Vec::new();
+
+        let is_var = |item: &&PatternItem| matches!(item,
+            PatternItem::TokenVar | PatternItem::BlockVar { .. } | PatternItem::SequenceVar | PatternItem::CaptureUntil);
+
+        let is_block = |item: &&PatternItem| matches!(item, PatternItem::BlockVar { .. });
+
+        for pattern in self.contents.keys() {
+            stats.total += 1;
+
+            if pattern.iter().any(is_var) {
+                stats.with_vars += 1;
+            }
+
+            if pattern.iter().any(is_block) {
+                stats.with_blocks += 1;
+            }
+
+            stats.max_pattern_len = stats.max_pattern_len.max(pattern.len());
+
+            if let Some(first) = pattern.first() {
+                if !first_tokens.contains(first) {
+                    first_tokens.push(*first);
+                }
+            }
+        }
+
+        stats.distinct_first_tokens = first_tokens.len();
+
+        stats
+    }
+
+    /// Renders the trie of loaded macros as a Graphviz DOT graph,
+    /// with variables shown as `$` in patterns and by their index in templates
+    pub fn to_dot(&self) -> String {
+        self.contents.to_dot(PatternItem::to_string, |found| describe_template(&found.template))
+    }
+
+    /// Expands only the tokens lying entirely within the byte range [start_byte, end_byte)
+    /// of the input, writing everything outside of it through verbatim.
+    /// Byte offsets are measured from the start of the first token.
+    pub fn expand_range(&self, input: &[Token], start_byte: usize, end_byte: usize, out_stream: &mut SimpleOutput) -> Result<()> {
+        let mut offset = 0;
+        let mut range_start = input.len();
+        let mut range_end = input.len();
+
+        for (index, token) in input.iter().enumerate() {
+            if offset >= start_byte && range_start == input.len() {
+                range_start = index;
+            }
+
+            if offset + token.value.len() > end_byte {
+                range_end = index;
+                break;
+            }
+
+            offset += token.value.len() + token.suffix.len();
+        }
+
+        let range_end = range_end.max(range_start);
+
+        out_stream.write(&render_tokens(&input[ .. range_start]))?;
+        self.expand_tokens(&input[range_start .. range_end], out_stream)?;
+        out_stream.write(&render_tokens(&input[range_end .. ]))
+    }
+
+    /// Writes each expanded region of the input as a hunk for review,
+    /// headed by the document name if it has one and its line in the input,
+    /// with the original text on lines prefixed by `-` and the expansion on lines prefixed by `+`.
+    /// Unexpanded text is left out.
+    pub fn expand_side_by_side(&self, input: &[Token], document_name: Option<&str>, out_stream: &mut SimpleOutput) -> Result<()> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut remaining = input;
+        let mut line = 1;
+
+        while !remaining.is_empty() {
+            let consumed = match self.expand_next(remaining)? {
+                Some((rendered, consumed)) => {
+                    let expanded = self.expand_nested(rendered, 0, deadline)?;
+
+                    let mut original = String::new();
+                    render_captured(&mut original, &remaining[ .. consumed]);
+
+                    match document_name {
+                        Some(document_name) => out_stream.write(&format!("@@ {} line {} @@\n", document_name, line))?,
+                        None => out_stream.write(&format!("@@ line {} @@\n", line))?
+                    }
+
+                    for original_line in original.lines() {
+                        out_stream.write(&format!("- {}\n", original_line))?;
+                    }
+
+                    for expanded_line in expanded.lines() {
+                        out_stream.write(&format!("+ {}\n", expanded_line))?;
+                    }
+
+                    consumed
+                },
+
+                None => 1
+            };
+
+            line += count_lines(&remaining[ .. consumed]);
+            remaining = &remaining[consumed .. ];
+        }
+
+        Ok(())
+    }
+
+    /// Expands the input into a token stream rather than writing it out,
+    /// so that it can be processed further before serialization.
+    /// Each expansion becomes a single owned token carrying the rendered template
+    /// and the suffix of the last token it consumed.
+    pub fn expand_to_tokens<'a>(&self, input: &[Token<'a>]) -> Result<Vec<ExpandedToken<'a>>> {
+        self.expand_named_to_tokens(input, None)
+    }
+
+    fn expand_named_to_tokens<'a>(&self, input: &[Token<'a>], document_name: Option<&str>) -> Result<Vec<ExpandedToken<'a>>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        self.expand_at_depth(input, document_name, 0, deadline)
+    }
+
+    /// Expands the rendered template of a macro found at depth, if expansion is recursive.
+    /// Identical templates at the same depth expand identically, so their results are memoized.
+    fn expand_nested(&self, rendered: String, depth: usize, deadline: Option<Instant>) -> Result<String> {
+        let recursion = match &self.recursion {
+            Some(recursion) => recursion,
+            None => return Ok(rendered)
+        };
+
+        let key = (rendered, depth + 1);
+
+        if let Some(memoized) = self.memo.borrow().get(&key) {
+            #[cfg(test)]
+            self.memo_hits.set(self.memo_hits.get() + 1);
+
+            if let Some(usage) = &self.usage {
+                let mut usage = usage.borrow_mut();
+
+                for (pattern, count) in &memoized.usage {
+                    *usage.entry(pattern.clone()).or_insert(0) += count;
+                }
+            }
+
+            return Ok(memoized.expanded.clone());
+        }
+
+        let side_outputs_len = self.side_outputs_len();
+        let usage_before = self.usage.as_ref().map(|usage| usage.borrow().clone());
+
+        let expanded: String = self.expand_at_depth(&recursion.tokenizer.tokenize(&key.0), None, depth + 1, deadline)?
+            .iter()
+            .map(|token| format!("{}{}", token.value, token.suffix))
+            .collect();
+
+        let usage = match (&self.usage, usage_before) {
+            (Some(usage), Some(usage_before)) => usage.borrow().iter()
+                .map(|(pattern, count)| (pattern.clone(), count - usage_before.get(pattern).unwrap_or(&0)))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+
+            _ => Vec::new()
+        };
+
+        let mut memo = self.memo.borrow_mut();
+
+        //Reusing an expansion that wrote to side outputs would leave those writes out
+        if memo.len() < MAX_MEMO_ENTRIES && self.side_outputs_len() == side_outputs_len {
+            memo.insert(key, Memoized { expanded: expanded.clone(), usage });
+        }
+
+        Ok(expanded)
+    }
+
+    fn expand_at_depth<'a>(&self, input: &[Token<'a>], document_name: Option<&str>, depth: usize, deadline: Option<Instant>) -> Result<Vec<ExpandedToken<'a>>> {
+        let max_depth = self.recursion.as_ref().map_or(0, |recursion| recursion.max_depth.min(MAX_RECURSION_DEPTH));
+
+        let trie_root = self.contents.as_view();
+
+        let mut output = Vec::new();
+        let mut remaining = input;
+
+        let mut line = 1;
+        let mut positions = 0;
+
+        //Set when an expansion's output has a different number of lines than the input it replaced,
+        //so the next text passed through needs a directive of its own
+        let mut resync = false;
+
+        while let Some(token) = remaining.first() {
+            if let Some(deadline) = deadline {
+                if positions % TIMEOUT_CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+                    return Err(Error::new(ErrorKind::TimedOut, "Expansion did not finish within the timeout"));
+                }
+            }
+
+            positions += 1;
+
+            let expansion = match &self.literal_index {
+                Some(literal_index) => literal_index.find(remaining),
+                None => Matcher::new(remaining, self.max_match_steps, self.strict_block_delimiters, self.case_insensitive).match_from(&trie_root, 0)?
+            };
+
+            if let (Some(Expansion { found, .. }), true) = (&expansion, depth > max_depth) {
+                return Err(Error::other(format!(
+                    "Recursive expansion of `{}` exceeded the maximum depth of {}", found.pattern, max_depth)));
+            }
+
+            let rendered = expansion.map(|Expansion { found, bindings, consumed }| {
+                (self.render_macro(found, &bindings), consumed)
+            });
+
+            match rendered {
+                Some((Ok(rendered), consumed)) => {
+                    let rendered = self.expand_nested(rendered, depth, deadline)?;
+                    let suffix = remaining[consumed - 1].suffix;
+                    let consumed_lines = count_lines(&remaining[ .. consumed]);
+
+                    //Nested expansions have no lines of their own in the input
+                    if let (Some(line_directives), 0) = (&self.line_directives, depth) {
+                        line_directives.push(&mut output, line, document_name);
+
+                        resync = rendered.matches('\n').count() + suffix.matches('\n').count() != consumed_lines;
+                    }
+
+                    line += consumed_lines;
+
+                    output.push(ExpandedToken {
+                        value: Cow::Owned(rendered),
+                        suffix: Cow::Borrowed(suffix)
+                    });
+
+                    remaining = &remaining[consumed .. ];
+                },
+
+                Some((Err(error), consumed)) => {
+                    if self.render_error_policy == RenderErrorPolicy::Abort {
+                        return Err(error);
+                    }
+
+                    let mut matched = String::new();
+                    render_captured(&mut matched, &remaining[ .. consumed]);
+
+                    eprintln!("Warning: {}, passing `{}` through unexpanded", error, matched);
+
+                    if let (Some(line_directives), true) = (&self.line_directives, resync) {
+                        line_directives.push(&mut output, line, document_name);
+                        resync = false;
+                    }
+
+                    output.extend(remaining[ .. consumed].iter().map(ExpandedToken::from));
+
+                    line += count_lines(&remaining[ .. consumed]);
+                    remaining = &remaining[consumed .. ];
+                },
+
+                None => {
+                    if let (Some(line_directives), true) = (&self.line_directives, resync) {
+                        line_directives.push(&mut output, line, document_name);
+                        resync = false;
+                    }
+
+                    output.push(ExpandedToken::from(token));
+
+                    line += count_lines(&remaining[ .. 1]);
+
+                    remaining = &remaining[1 .. ];
+                }
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+fn count_lines(tokens: &[Token]) -> usize {
+    tokens.iter()
+        .map(|token| token.value.matches('\n').count() + token.suffix.matches('\n').count())
+        .sum()
+}
+
+/// Whether two patterns conflict in the trie, by one being a prefix of the other
+fn patterns_conflict<'p, I>(a: I, b: &[PatternItem]) -> bool
+    where
+        I: IntoIterator<Item=&'p PatternItem> {
+
+    a.into_iter().zip(b).all(|(a, b)| a == b)
+}
+
+/// Reports that new_macro's pattern conflicts with that of existing, if it is known.
+/// Conflicts are not syntax errors, so recovering from syntax errors does not skip them.
+fn conflict_error(new_macro: &Macro, existing: Option<&Macro>) -> Error {
+    let mut message = format!("Macro pattern `{}` on line {} conflicts", new_macro.pattern, new_macro.site.line);
+
+    if let Some(existing) = existing {
+        message.push_str(&format!(" with `{}` on line {}", existing.pattern, existing.site.line));
+
+        if existing.site.file != new_macro.site.file {
+            message.push_str(" of an earlier source");
+        }
+    }
+
+    Error::new(ErrorKind::AlreadyExists, message)
+}
+
+fn syntax_error(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// If tokens start with a definition, returns the feature guarding it (if any)
+/// and the position of its `define` or `fragment` keyword
+fn definition_header<'a>(tokens: &[Token<'a>]) -> Option<(Option<&'a str>, usize)> {
+    let is_keyword = |token: &Token| token.value == DEFINE_KEYWORD || token.value == FRAGMENT_KEYWORD;
+
+    match tokens {
+        [start, keyword, ..] if start.value == DEFINITION_START && is_keyword(keyword) => Some((None, 1)),
+
+        [start, open, guard, condition, close, keyword, ..]
+            if start.value == DEFINITION_START
+                && open.value == BlockDelimiter::SquareBracket.open()
+                && guard.value == GUARD_KEYWORD
+                && condition.value.starts_with(FEATURE_SETTING)
+                && close.value == BlockDelimiter::SquareBracket.close()
+                && is_keyword(keyword) => {
+
+            Some((Some(&condition.value[FEATURE_SETTING.len() .. ]), 5))
+        },
+
+        _ => None
+    }
+}
+
+/// Finds a `#` starting a line within a definition that is followed by a near miss
+/// of a definition keyword, such as `#defne`, which would otherwise be read as template text
+fn find_misspelled_header(tokens: &[Token]) -> Option<usize> {
+    let is_near_miss = |value: &str| [DEFINE_KEYWORD, FRAGMENT_KEYWORD].iter()
+        .any(|keyword| value != *keyword && edit_distance(value, keyword) <= 2);
+
+    (1 .. tokens.len().saturating_sub(1)).find(|&index| {
+        tokens[index].value == DEFINITION_START
+            && tokens[index].suffix.is_empty()
+            && tokens[index - 1].suffix.contains('\n')
+            && is_near_miss(tokens[index + 1].value)
+    })
+}
+
+/// The number of single character insertions, deletions and substitutions turning a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0 ..= b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Splits the leading options off of a definition
+fn parse_options<'t, 'a>(tokens: &'t [Token<'a>]) -> Result<(MacroOptions, &'t [Token<'a>])> {
+    let mut options = MacroOptions::default();
+    let mut remaining = tokens;
+
+    while let Some(token) = remaining.first() {
+        if !token.value.starts_with(OPTION_SIGIL) {
+            break;
+        }
+
+        let name = &token.value[OPTION_SIGIL.len() .. ];
+
+        if name == BOUNDARY_OPTION {
+            options.requires_boundary = true;
+        } else {
+            let transform = Transform::from_name(name)
+                .ok_or_else(|| syntax_error(format!("Unknown option `{}`", token.value)))?;
+
+            options.transforms.push(transform);
+        }
+
+        remaining = &remaining[1 .. ];
+    }
+
+    Ok((options, remaining))
+}
+
+/// Returns the name of the variable a token refers to, if it is one
+fn var_name<'a>(token: &Token<'a>) -> Option<&'a str> {
+    if token.value.len() > VAR_SIGIL.len() && token.value.starts_with(VAR_SIGIL) {
+        Some(&token.value[VAR_SIGIL.len() .. ])
+    } else {
+        None
+    }
+}
+
+fn declare_var(var_names: &mut Vec<String>, name: &str) -> Result<()> {
+    if var_names.iter().any(|existing| existing == name) {
+        return Err(syntax_error(format!("Variable `{}{}` is bound more than once", VAR_SIGIL, name)));
+    }
+
+    if var_names.len() > u8::MAX as usize {
+        return Err(syntax_error("Macro pattern has too many variables".to_string()));
+    }
+
+    var_names.push(name.to_string());
+
+    Ok(())
+}
+
+/// A block variable is a lone variable wrapped in a pair of delimiters, e.g. `{ $block }`
+fn block_var<'a>(tokens: &[Token<'a>]) -> Option<(BlockDelimiter, &'a str)> {
+    match tokens {
+        [open, var, close, ..] => {
+            let block_delim = BlockDelimiter::from_open(open.value)?;
+            let name = var_name(var)?;
+
+            //Delimited sequence and capture until variables are matched as such, so that they keep their names
+            if close.value == block_delim.close() && !name.ends_with(SEQUENCE_SUFFIX) && !name.contains(UNTIL_SIGIL) {
+                Some((block_delim, name))
+            } else {
+                None
+            }
+        },
+
+        _ => None
+    }
+}
+
+fn parse_pattern(tokens: &[Token], var_names: &mut Vec<String>) -> Result<Vec<PatternItem>> {
+    let mut pattern = Vec::new();
+    let mut remaining = tokens;
+
+    //The indexes of the names in var_names declared by token variables, which may be referred back to
+    let mut token_vars = Vec::new();
+
+    while let Some(token) = remaining.first() {
+        if let Some((block_delim, name)) = block_var(remaining) {
+            declare_var(var_names, name)?;
+            pattern.push(PatternItem::BlockVar { block_delim });
+            remaining = &remaining[3 .. ];
+        } else if let Some(name) = var_name(token) {
+            if let Some(split) = name.find(UNTIL_SIGIL).filter(|&split| split > 0 && split + UNTIL_SIGIL.len() < name.len()) {
+                declare_var(var_names, &name[ .. split])?;
+                pattern.push(PatternItem::CaptureUntil);
+                pattern.push(PatternItem::UntilDelimiter { value: name[split + UNTIL_SIGIL.len() .. ].to_string() });
+            } else if name.ends_with(SEQUENCE_SUFFIX) && name.len() > SEQUENCE_SUFFIX.len() {
+                declare_var(var_names, &name[ .. name.len() - SEQUENCE_SUFFIX.len()])?;
+                pattern.push(PatternItem::SequenceVar);
+            } else if let Some(index) = var_names.iter().position(|existing| existing == name) {
+                if !token_vars.contains(&index) {
+                    return Err(syntax_error(format!("Only token variables can be referred back to, not `{}{}`", VAR_SIGIL, name)));
+                }
+
+                pattern.push(PatternItem::MatchTokenVar { index: index as u8 });
+            } else {
+                token_vars.push(var_names.len());
+                declare_var(var_names, name)?;
+                pattern.push(PatternItem::TokenVar);
+            }
+
+            remaining = &remaining[1 .. ];
+        } else if token.value == LOOKAHEAD_START {
+            pattern.push(PatternItem::FollowedBy);
+            remaining = &remaining[1 .. ];
+        } else if token.value.len() > FRAGMENT_SIGIL.len() && token.value.starts_with(FRAGMENT_SIGIL) {
+            pattern.push(PatternItem::Fragment { name: token.value[FRAGMENT_SIGIL.len() .. ].to_string() });
+            remaining = &remaining[1 .. ];
+        } else {
+            pattern.push(PatternItem::MatchToken {
+                value: token.value.to_string(),
+                requires_boundary: false
+            });
+            remaining = &remaining[1 .. ];
+        }
+    }
+
+    Ok(pattern)
+}
+
+/// A lookahead must come after at least one consumed item, so that matches make progress,
+/// and must itself contain at least one item
+fn validate_lookahead(pattern: &[PatternItem]) -> Result<()> {
+    let lookaheads: Vec<usize> = pattern.iter()
+        .enumerate()
+        .filter(|(_, item)| **item == PatternItem::FollowedBy)
+        .map(|(index, _)| index)
+        .collect();
+
+    match lookaheads.as_slice() {
+        [] => Ok(()),
+        [index] if *index > 0 && *index + 1 < pattern.len() => Ok(()),
+        [_] => Err(syntax_error(format!("`{}` must have pattern items both before and after it", LOOKAHEAD_START))),
+        _ => Err(syntax_error(format!("A pattern may only contain one `{}`", LOOKAHEAD_START)))
+    }
+}
+
+/// The suffix of the final template token is dropped,
+/// the suffix of the last consumed input token is used in its place
+fn parse_template(tokens: &[Token], var_names: &[String], context: &HashMap<String, String>) -> Result<Template> {
+    let mut template = Vec::new();
+    let mut position = 0;
+
+    while position < tokens.len() {
+        let token = &tokens[position];
+        position += 1;
+
+        let suffix = if position == tokens.len() { "" } else { token.suffix };
+
+        if token.value == FOR_EACH_KEYWORD {
+            if let Some(for_each) = parse_for_each(&tokens[position .. ], var_names, context)? {
+                let (item, consumed) = for_each;
+
+                template.push(item);
+                position += consumed;
+
+                //The for each takes the suffix of its closing delimiter
+                let last_suffix = if position == tokens.len() { "" } else { tokens[position - 1].suffix };
+
+                if !last_suffix.is_empty() {
+                    template.push(TemplateItem::Text { data: last_suffix.to_string() });
+                }
+
+                continue;
+            }
+        }
+
+        if token.value == TO_FILE_KEYWORD {
+            if let [name, open, ..] = &tokens[position .. ] {
+                if open.value == BlockDelimiter::CurlyBracket.open() {
+                    let BlockParse { block_tokens, remaining } = parse_block(&tokens[position + 2 .. ], &BlockDelimiter::CurlyBracket)?;
+
+                    //The suffix of the closing delimiter goes to neither output
+                    template.push(TemplateItem::ToFile {
+                        name: name.value.to_string(),
+                        body: coalesce_text(parse_template(block_tokens, var_names, context)?)
+                    });
+
+                    position = tokens.len() - remaining.len();
+
+                    continue;
+                }
+            }
+        }
+
+        if token.value.len() > CONTEXT_SIGIL.len() && token.value.starts_with(CONTEXT_SIGIL) {
+            let written = &token.value[CONTEXT_SIGIL.len() .. ];
+
+            let (name, default) = match written.find(CONTEXT_DEFAULT_SEPARATOR) {
+                Some(split) => (&written[ .. split], Some(written[split + CONTEXT_DEFAULT_SEPARATOR.len() .. ].to_string())),
+                None => (written, None)
+            };
+
+            if default.is_none() && !context.contains_key(name) {
+                return Err(syntax_error(format!("Template uses undefined context variable `{}`", name)));
+            }
+
+            template.push(TemplateItem::ContextVar { name: name.to_string(), default });
+
+            if !suffix.is_empty() {
+                template.push(TemplateItem::Text { data: suffix.to_string() });
+            }
+
+            continue;
+        }
+
+        if token.value == DEBUG_BINDINGS_KEYWORD && !var_names.iter().any(|name| *name == token.value[VAR_SIGIL.len() .. ]) {
+            template.push(TemplateItem::DebugBindings { names: var_names.to_vec() });
+
+            if !suffix.is_empty() {
+                template.push(TemplateItem::Text { data: suffix.to_string() });
+            }
+
+            continue;
+        }
+
+        match var_name(token) {
+            Some(name) => {
+                //Whether an unbound variable is allowed is up to the macro reading the template
+                match var_names.iter().position(|existing| existing == name) {
+                    Some(index) => template.push(TemplateItem::Var { index: index as u8 }),
+                    None => template.push(TemplateItem::Unbound { name: name.to_string() })
+                }
+
+                if !suffix.is_empty() {
+                    template.push(TemplateItem::Text { data: suffix.to_string() });
+                }
+            },
+
+            None if token.value.ends_with(LINE_CONTINUATION) && suffix.contains('\n') => {
+                let continued = &token.value[ .. token.value.len() - LINE_CONTINUATION.len()];
+
+                template.push(TemplateItem::Text { data: continued.to_string() });
+            },
+
+            None => {
+                template.push(TemplateItem::Text { data: format!("{}{}", token.value, suffix) });
+            }
+        }
+    }
+
+    Ok(template)
+}
+
+/// Drops the escape character from the text of template, keeping the characters it escaped
+fn unescape_template(template: Template, escape_char: char) -> Template {
+    template.into_iter()
+        .map(|item| match item {
+            TemplateItem::Text { data } => TemplateItem::Text { data: unescape(&data, escape_char) },
+
+            TemplateItem::ForEach { var_index, body, separator } => TemplateItem::ForEach {
+                var_index,
+                body: unescape_template(body, escape_char),
+                separator: separator.map(|separator| unescape_template(separator, escape_char))
+            },
+
+            TemplateItem::ToFile { name, body } => TemplateItem::ToFile {
+                name,
+                body: unescape_template(body, escape_char)
+            },
+
+            item => item
+        })
+        .collect()
+}
+
+/// Drops each escape_char before another character, except a line break, which the tokenizer does not escape
+fn unescape(text: &str, escape_char: char) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&escaped) if c == escape_char && escaped != '\n' && escaped != '\r' => {
+                unescaped.push(escaped);
+                chars.next();
+            },
+
+            _ => unescaped.push(c)
+        }
+    }
+
+    unescaped
+}
+
+/// Parses the rest of a for each, `$xs ( separator ) { body }`, from just after `$for`,
+/// returning it and the number of tokens it used.
+/// If the tokens do not have that shape then `$for` is an ordinary variable.
+fn parse_for_each(tokens: &[Token], var_names: &[String], context: &HashMap<String, String>) -> Result<Option<(TemplateItem, usize)>> {
+    let (var_token, mut remaining) = match tokens {
+        [var_token, rest @ ..] => (var_token, rest),
+        [] => return Ok(None)
+    };
+
+    let var_index = match var_name(var_token).and_then(|name| var_names.iter().position(|existing| existing == name)) {
+        Some(var_index) => var_index,
+        None => return Ok(None)
+    };
+
+    let mut separator = None;
+
+    if remaining.first().map(|token| token.value) == Some(BlockDelimiter::Parenthesis.open()) {
+        let BlockParse { block_tokens, remaining: after } = parse_block(&remaining[1 .. ], &BlockDelimiter::Parenthesis)?;
+
+        //The separator is written exactly as it appears between the parentheses
+        separator = Some(block_tokens.iter()
+            .map(|token| TemplateItem::Text { data: format!("{}{}", token.value, token.suffix) })
+            .collect());
+
+        remaining = after;
+    }
+
+    if remaining.first().map(|token| token.value) != Some(BlockDelimiter::CurlyBracket.open()) {
+        return Ok(None);
+    }
+
+    let BlockParse { block_tokens, remaining } = parse_block(&remaining[1 .. ], &BlockDelimiter::CurlyBracket)?;
+
+    let mut body_var_names = var_names.to_vec();
+    body_var_names.push(ELEMENT_VAR.to_string());
+    body_var_names.push(ELEMENT_NUMBER_VAR.to_string());
+
+    let item = TemplateItem::ForEach {
+        var_index: var_index as u8,
+        body: coalesce_text(parse_template(block_tokens, &body_var_names, context)?),
+        separator: separator.map(coalesce_text)
+    };
+
+    Ok(Some((item, tokens.len() - remaining.len())))
+}
+
+/// Merges adjacent Text items, so that rendering appends fewer pieces
+fn coalesce_text(template: Template) -> Template {
+    let mut coalesced: Template = Vec::with_capacity(template.len());
+
+    for item in template {
+        match (coalesced.last_mut(), item) {
+            (Some(TemplateItem::Text { data }), TemplateItem::Text { data: next_data }) => {
+                data.push_str(&next_data);
+            },
+
+            (_, item) => {
+                coalesced.push(item);
+            }
+        }
+    }
+
+    coalesced
+}
+
+/// Splits tokens, which start just after an opening delimiter,
+/// at the delimiter that closes it
+fn parse_block<'a>(tokens: &'a [Token<'a>], block_delim: &BlockDelimiter) -> Result<BlockParse<'a>> {
+    let mut open_delims = vec![block_delim.clone()];
+
+    for (index, token) in tokens.iter().enumerate() {
+        if let Some(inner_delim) = BlockDelimiter::from_open(token.value) {
+            open_delims.push(inner_delim);
+        } else if let Some(close_delim) = BlockDelimiter::from_close(token.value) {
+            if open_delims.pop() != Some(close_delim) {
+                return Err(syntax_error(format!("Unbalanced `{}`", token.value)));
+            }
+
+            if open_delims.is_empty() {
+                return Ok(BlockParse {
+                    block_tokens: &tokens[ .. index],
+                    remaining: &tokens[index + 1 .. ]
+                });
+            }
+        }
+    }
+
+    Err(syntax_error(format!("Missing `{}`", block_delim.close())))
+}
+
+/// Splits tokens at each separator token that is not nested within a block.
+/// Like parse_block this tracks a stack of open delimiters,
+/// but a closing delimiter that does not match the innermost block is ignored rather than an error.
+fn split_top_level<'t, 'a>(tokens: &'t [Token<'a>], separator: &str) -> Vec<&'t [Token<'a>]> {
+    let mut open_delims = Vec::new();
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+
+    for (index, token) in tokens.iter().enumerate() {
+        if let Some(inner_delim) = BlockDelimiter::from_open(token.value) {
+            open_delims.push(inner_delim);
+        } else if let Some(close_delim) = BlockDelimiter::from_close(token.value) {
+            if open_delims.last() == Some(&close_delim) {
+                open_delims.pop();
+            }
+        } else if token.value == separator && open_delims.is_empty() {
+            parts.push(&tokens[part_start .. index]);
+            part_start = index + 1;
+        }
+    }
+
+    parts.push(&tokens[part_start .. ]);
+
+    parts
+}
+
+/// Searches the trie for a macro matching the start of the input.
+///
+/// Candidates are tried depth first, preferring literal tokens, then blocks,
+/// then token variables and finally sequence variables.
+/// Sequence variables make this a backtracking search, since each one may retry
+/// every run length up to the end of its enclosing block.
+/// A pattern with k sequence variables can therefore take O(n^k) steps
+/// on input that almost matches it, so the total number of steps is bounded
+/// and exceeding the bound is an error.
+struct Matcher<'a> {
+    input: &'a [Token<'a>],
+    bindings: Vec<&'a [Token<'a>]>,
+
+    /// The position at which the lookahead of the current pattern began, if it has
+    lookahead_start: Option<usize>,
+
+    steps: usize,
+    max_steps: usize,
+
+    strict_block_delimiters: bool,
+    case_insensitive: bool
+}
+
+impl<'a> Matcher<'a> {
+    fn new(input: &'a [Token<'a>], max_steps: usize, strict_block_delimiters: bool, case_insensitive: bool) -> Self {
+        Matcher {
+            input,
+            bindings: Vec::new(),
+            lookahead_start: None,
+            steps: 0,
+            max_steps,
+            strict_block_delimiters,
+            case_insensitive
+        }
+    }
+
+    /// The form of an input token's value that literal pattern tokens are stored in
+    fn literal_key(&self, value: &str) -> String {
+        if self.case_insensitive {
+            value.to_lowercase()
+        } else {
+            value.to_string()
+        }
+    }
+
+    fn match_from<'m>(&mut self, view: &HashTrieView<'m, PatternItem, Rc<Macro>>, position: usize) -> Result<Option<Expansion<'m, 'a>>> {
+        self.steps += 1;
+
+        if self.steps > self.max_steps {
+            return Err(Error::other(format!(
+                "Matching macros at `{}` exceeded the limit of {} backtracking steps",
+                self.input[0].value,
+                self.max_steps)));
+        }
+
+        let consumed = self.lookahead_start.unwrap_or(position);
+
+        //A match consuming nothing, such as an empty capture up to a delimiter, would never advance
+        if let (Some(found), true) = (view.value(), consumed > 0) {
+            return Ok(Some(Expansion {
+                found,
+                bindings: self.bindings.clone(),
+                consumed
+            }));
+        }
+
+        if let Some(next_view) = view.descend(PatternItem::FollowedBy) {
+            self.lookahead_start = Some(position);
+
+            if let Some(expansion) = self.match_from(&next_view, position)? {
+                return Ok(Some(expansion));
+            }
+
+            self.lookahead_start = None;
+        }
+
+        let input = self.input;
+
+        let token = match input.get(position) {
+            Some(token) => token,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        let at_boundary = !token.suffix.is_empty() || position + 1 == input.len();
+        let boundary_requirements: &[bool] = if at_boundary { &[false, true] } else { &[false] };
+
+        for &requires_boundary in boundary_requirements {
+            let literal = PatternItem::MatchToken {
+                value: self.literal_key(token.value),
+                requires_boundary
+            };
+
+            if let Some(next_view) = view.descend(literal) {
+                if let Some(expansion) = self.match_from(&next_view, position + 1)? {
+                    return Ok(Some(expansion));
+                }
+            }
+        }
+
+        //Bindings keep the input's own form of the tokens, so back-references compare normalized copies
+        for index in 0 .. self.bindings.len() {
+            if let Some(next_view) = view.descend(PatternItem::MatchTokenVar { index: index as u8 }) {
+                if let [bound] = self.bindings[index] {
+                    if self.literal_key(bound.value) == self.literal_key(token.value) {
+                        if let Some(expansion) = self.match_from(&next_view, position + 1)? {
+                            return Ok(Some(expansion));
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(block_delim) = BlockDelimiter::from_open(token.value) {
+            let block_var = PatternItem::BlockVar { block_delim: block_delim.clone() };
+
+            if let Some(next_view) = view.descend(block_var) {
+                if let Ok(BlockParse { block_tokens, remaining }) = parse_block(&input[position + 1 .. ], &block_delim) {
+                    if let Some(expansion) = self.match_binding(&next_view, block_tokens, input.len() - remaining.len())? {
+                        return Ok(Some(expansion));
+                    }
+                }
+            }
+        }
+
+        if let Some(next_view) = view.descend(PatternItem::TokenVar) {
+            if let Some(expansion) = self.match_binding(&next_view, &input[position .. position + 1], position + 1)? {
+                return Ok(Some(expansion));
+            }
+        }
+
+        if let Some(next_view) = view.descend(PatternItem::SequenceVar) {
+            let mut ends = Vec::new();
+            let mut end = position;
+
+            while let Some(next_end) = next_group_end(input, end) {
+                end = next_end;
+                ends.push(end);
+            }
+
+            //The longest run that lets the rest of the pattern match wins
+            for &end in ends.iter().rev() {
+                if let Some(expansion) = self.match_binding(&next_view, &input[position .. end], end)? {
+                    return Ok(Some(expansion));
+                }
+            }
+        }
+
+        if let Some(until_view) = view.descend(PatternItem::CaptureUntil) {
+            let mut open_delims = Vec::new();
+
+            for end in position .. input.len() {
+                let token = &input[end];
+
+                if open_delims.is_empty() {
+                    let delimiter = PatternItem::UntilDelimiter { value: self.literal_key(token.value) };
+
+                    //Only the first delimiter is tried, so the capture runs up to it and no further
+                    if let Some(next_view) = until_view.descend(delimiter) {
+                        if let Some(expansion) = self.match_binding(&next_view, &input[position .. end], end)? {
+                            return Ok(Some(expansion));
+                        }
+
+                        break;
+                    }
+                }
+
+                if let Some(inner_delim) = BlockDelimiter::from_open(token.value) {
+                    open_delims.push(inner_delim);
+                } else if let Some(close_delim) = BlockDelimiter::from_close(token.value) {
+                    //The capture cannot extend past the end of its enclosing block
+                    if open_delims.pop() != Some(close_delim) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.strict_block_delimiters {
+            if let Some(found_delim) = BlockDelimiter::from_open(token.value) {
+                let all_delims = [BlockDelimiter::SquareBracket, BlockDelimiter::CurlyBracket, BlockDelimiter::Parenthesis];
+
+                for expected_delim in all_delims.iter().filter(|delim| **delim != found_delim) {
+                    if view.descend(PatternItem::BlockVar { block_delim: expected_delim.clone() }).is_some() {
+                        return Err(syntax_error(format!("Expected `{}` here, found `{}`", expected_delim.open(), found_delim.open())));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Continues matching from next_position with captured bound to the next variable
+    fn match_binding<'m>(
+        &mut self,
+        next_view: &HashTrieView<'m, PatternItem, Rc<Macro>>,
+        captured: &'a [Token<'a>],
+        next_position: usize) -> Result<Option<Expansion<'m, 'a>>> {
+
+        self.bindings.push(captured);
+
+        let result = self.match_from(next_view, next_position);
+
+        if let Ok(None) = result {
+            self.bindings.pop();
+        }
+
+        result
+    }
+}
+
+/// A trie over token values for macro sets made up only of literal tokens.
+/// These are found in a single walk from each position, without the
+/// per-token key allocation, variable candidates or backtracking of Matcher.
+struct LiteralIndex {
+    trie: OverlappingTrie<String, Rc<Macro>>
+}
+
+impl LiteralIndex {
+    fn new() -> Self {
+        LiteralIndex {
+            trie: OverlappingTrie::new()
+        }
+    }
+
+    /// Inserts a pattern already known not to conflict with any other
+    fn insert(&mut self, values: &[&str], found: Rc<Macro>) {
+        self.trie.insert(values.iter().map(|value| value.to_string()), found);
+    }
+
+    fn remove(&mut self, values: &[&str]) {
+        self.trie.remove(values.iter().map(|value| value.to_string()));
+    }
+
+    fn find<'a>(&self, input: &'a [Token<'a>]) -> Option<Expansion<'_, 'a>> {
+        //Literal patterns are prefix free, so the longest match is the only one
+        let (consumed, found) = self.trie.longest_match(input.iter().map(|token| token.value))?;
+
+        Some(Expansion {
+            found,
+            bindings: Vec::new(),
+            consumed
+        })
+    }
+}
+
+/// Returns the end of the token or balanced block starting at start,
+/// or None if the input ends or its enclosing block closes there
+fn next_group_end(input: &[Token], start: usize) -> Option<usize> {
+    let token = input.get(start)?;
+
+    if BlockDelimiter::from_close(token.value).is_some() {
+        return None;
+    }
+
+    match BlockDelimiter::from_open(token.value) {
+        Some(block_delim) => {
+            let BlockParse { remaining, .. } = parse_block(&input[start + 1 .. ], &block_delim).ok()?;
+
+            Some(input.len() - remaining.len())
+        },
+
+        None => Some(start + 1)
+    }
+}
+
+impl Macros {
+    fn render_macro(&self, found: &Macro, bindings: &[&[Token]]) -> Result<String> {
+        let mut side_outputs = Vec::new();
+        let rendered = render(&found.template, bindings, self.unbound_placeholders, &self.context, &mut side_outputs)
+            .map_err(|error| Error::new(error.kind(), format!("{} in the template of `{}`", error, found.pattern)))?;
+
+        if let Some(usage) = &self.usage {
+            *usage.borrow_mut().entry(found.pattern.clone()).or_insert(0) += 1;
+        }
+
+        //Only a template that renders successfully writes to its side outputs
+        let mut all_side_outputs = self.side_outputs.borrow_mut();
+
+        for (name, side_rendered) in side_outputs {
+            let output = all_side_outputs.entry(name).or_default();
+            output.push_str(&side_rendered);
+            output.push('\n');
+        }
+
+        Ok(found.transforms.iter().fold(rendered, |rendered, transform| transform.apply(rendered)))
+    }
+}
+
+/// Renders a template with its variables bound to the captured tokens.
+/// Variables bound by no pattern are an error, unless placeholders is set,
+/// in which case they are rendered as `<?name>` for a later pass to fill.
+/// Sections written to side outputs are appended to side_outputs by name.
+fn render(
+    template: &Template,
+    bindings: &[&[Token]],
+    placeholders: bool,
+    context: &HashMap<String, String>,
+    side_outputs: &mut Vec<(String, String)>) -> Result<String> {
+
+    let mut rendered = String::new();
+
+    for item in template {
+        match item {
+            TemplateItem::Text { data } => {
+                rendered.push_str(data);
+            },
+
+            TemplateItem::Var { index } => {
+                match bindings.get(*index as usize) {
+                    Some(captured) => {
+                        render_captured(&mut rendered, captured);
+                    },
+
+                    None => {
+                        return Err(syntax_error(format!("Template variable {} is not bound", index)));
+                    }
+                }
+            },
+
+            TemplateItem::ForEach { var_index, body, separator } => {
+                match bindings.get(*var_index as usize) {
+                    Some(captured) => {
+                        for (index, element) in captured.iter().enumerate() {
+                            if let (Some(separator), true) = (separator, index > 0) {
+                                rendered.push_str(&render(separator, bindings, placeholders, context, side_outputs)?);
+                            }
+
+                            let number = (index + 1).to_string();
+                            let number_token = [Token::new(&number, "")];
+                            let element_token = [Token::new(element.value, "")];
+
+                            let mut body_bindings = bindings.to_vec();
+                            body_bindings.push(&element_token);
+                            body_bindings.push(&number_token);
+
+                            rendered.push_str(&render(body, &body_bindings, placeholders, context, side_outputs)?);
+                        }
+                    },
+
+                    None => {
+                        return Err(syntax_error(format!("Template variable {} is not bound", var_index)));
+                    }
+                }
+            },
+
+            TemplateItem::Unbound { name } => {
+                if !placeholders {
+                    return Err(syntax_error(format!("Template variable `{}{}` is not bound", VAR_SIGIL, name)));
+                }
+
+                rendered.push_str(&format!("<?{}>", name));
+            },
+
+            TemplateItem::ContextVar { name, default } => {
+                match context.get(name).or(default.as_ref()) {
+                    Some(value) => {
+                        rendered.push_str(value);
+                    },
+
+                    None => {
+                        return Err(syntax_error(format!("Context variable `{}` is not set", name)));
+                    }
+                }
+            },
+
+            TemplateItem::ToFile { name, body } => {
+                let side_rendered = render(body, bindings, placeholders, context, side_outputs)?;
+
+                side_outputs.push((name.clone(), side_rendered));
+            },
+
+            TemplateItem::DebugBindings { names } => {
+                let described: Vec<String> = names.iter()
+                    .enumerate()
+                    .map(|(index, name)| match bindings.get(index) {
+                        Some(captured) => {
+                            let mut value = String::new();
+                            render_captured(&mut value, captured);
+
+                            format!("{}{} = `{}`", VAR_SIGIL, name, value)
+                        },
+
+                        None => format!("{}{} unbound", VAR_SIGIL, name)
+                    })
+                    .collect();
+
+                rendered.push_str(&format!("[debug: {}]", described.join(", ")));
+            }
+        }
+    }
+
+    Ok(rendered)
+}
+
+/// Normalizes the literal tokens of a pattern for case insensitive matching
+fn lowercase_literals(pattern: &mut [PatternItem]) {
+    for item in pattern {
+        match item {
+            PatternItem::MatchToken { value, .. } | PatternItem::UntilDelimiter { value } => {
+                *value = value.to_lowercase();
+            },
+
+            _ => {}
+        }
+    }
+}
+
+fn describe_pattern(pattern: &[PatternItem]) -> String {
+    pattern.iter()
+        .map(PatternItem::to_string)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn uses_debug_bindings(template: &Template) -> bool {
+    template.iter().any(|item| match item {
+        TemplateItem::DebugBindings { .. } => true,
+        TemplateItem::ForEach { body, .. } | TemplateItem::ToFile { body, .. } => uses_debug_bindings(body),
+        _ => false
+    })
+}
+
+/// The names of the variables template uses that no pattern binds, in order of use
+fn unbound_vars(template: &Template) -> Vec<&str> {
+    template.iter()
+        .flat_map(|item| match item {
+            TemplateItem::Unbound { name } => vec![name.as_str()],
+            TemplateItem::ForEach { body, .. } | TemplateItem::ToFile { body, .. } => unbound_vars(body),
+            _ => Vec::new()
+        })
+        .collect()
+}
+
+fn describe_template(template: &Template) -> String {
+    template.iter()
+        .map(|item| match item {
+            TemplateItem::Text { data } => data.clone(),
+            TemplateItem::Var { index } => format!("{}{}", VAR_SIGIL, index),
+            TemplateItem::Unbound { name } => format!("{}{}", VAR_SIGIL, name),
+            TemplateItem::ContextVar { name, .. } => format!("{}{}", CONTEXT_SIGIL, name),
+            TemplateItem::ForEach { var_index, body, .. } => format!("{} {}{} {{ {} }}", FOR_EACH_KEYWORD, VAR_SIGIL, var_index, describe_template(body)),
+            TemplateItem::ToFile { name, body } => format!("{} {} {{ {} }}", TO_FILE_KEYWORD, name, describe_template(body)),
+            TemplateItem::DebugBindings { .. } => DEBUG_BINDINGS_KEYWORD.to_string()
+        })
+        .collect()
+}
+
+/// An upper bound on the length of rendering template with bindings, ignoring transforms
+fn estimate_template(template: &Template, bindings: &[&[Token]], context: &HashMap<String, String>) -> usize {
+    let captured_len = |index: u8| bindings.get(index as usize)
+        .map_or(0, |captured| captured.iter().map(|token| token.value.len() + token.suffix.len()).sum());
+
+    template.iter()
+        .map(|item| match item {
+            TemplateItem::Text { data } => data.len(),
+
+            TemplateItem::Var { index } => captured_len(*index),
+
+            TemplateItem::Unbound { name } => name.len() + 3,
+
+            TemplateItem::ContextVar { name, default } => context.get(name)
+                .or(default.as_ref())
+                .map_or(0, String::len),
+
+            TemplateItem::ForEach { var_index, body, separator } => {
+                let elements = bindings.get(*var_index as usize).map_or(0, |captured| captured.len());
+
+                //Each element is at most the whole capture, and its number at most the count of them
+                let number = elements.to_string();
+                let number_token = [Token::new(&number, "")];
+                let whole_capture = bindings.get(*var_index as usize).cloned().unwrap_or(&[]);
+
+                let mut body_bindings = bindings.to_vec();
+                body_bindings.push(whole_capture);
+                body_bindings.push(&number_token);
+
+                let separator_len = separator.as_ref().map_or(0, |separator| estimate_template(separator, bindings, context));
+
+                elements * (estimate_template(body, &body_bindings, context) + separator_len)
+            },
+
+            TemplateItem::ToFile { .. } => 0,
+
+            //Each binding is written as `$name = `value`, ` or `$name unbound, `, around `[debug: ]`
+            TemplateItem::DebugBindings { names } => names.iter()
+                .enumerate()
+                .map(|(index, name)| VAR_SIGIL.len() + name.len() + captured_len(index as u8) + 9)
+                .sum::<usize>() + 9
+        })
+        .sum()
+}
+
+/// Captured tokens are rendered without the suffix of the last one
+fn render_captured(rendered: &mut String, captured: &[Token]) {
+    for (position, token) in captured.iter().enumerate() {
+        rendered.push_str(token.value);
+
+        if position + 1 < captured.len() {
+            rendered.push_str(token.suffix);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::{ simplify_output, SharedBuffer };
+    use crate::tokenizer::Tokenizer;
+
+    fn macros_from(definitions: &str) -> Macros {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        macros.read_macros(&tokenizer.tokenize(definitions)).unwrap();
+
+        macros
+    }
+
+    fn expand(macros: &Macros, input: &str) -> String {
+        let tokenizer = Tokenizer::default();
+        let buffer = SharedBuffer::default();
+
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+        macros.expand_tokens(&tokenizer.tokenize(input), &mut out_stream).unwrap();
+
+        buffer.contents()
+    }
+
+    fn expand_via_tokens(macros: &Macros, input: &str) -> String {
+        let tokenizer = Tokenizer::default();
+
+        macros.expand_to_tokens(&tokenizer.tokenize(input)).unwrap()
+            .iter()
+            .map(|token| format!("{}{}", token.value, token.suffix))
+            .collect()
+    }
+
+    #[test]
+    fn expands_readme_example() {
+        let macros = macros_from("#define if ( $cond ) { $block } :\nif $cond:\n  $block\n");
+
+        let output = expand(&macros, "if (a == b) {\n  some_func();\n}\n");
+
+        assert_eq!(output, "if a == b:\n  some_func();\n");
+    }
+
+    #[test]
+    fn passes_through_unmatched_tokens() {
+        let macros = macros_from("#define foo : bar");
+
+        assert_eq!(expand(&macros, "a  foo\tb\n"), "a  bar\tb\n");
+    }
+
+    #[test]
+    fn rewrites_literal_words() {
+        let macros = macros_from("#define old name : new_name #define colour : color");
+
+        assert_eq!(expand(&macros, "the old name and old names, colour colour\n"), "the new_name and old names, color color\n");
+        assert_eq!(expand(&macros, "nothing to do\n"), "nothing to do\n");
+        assert_eq!(expand(&macros, ""), "");
+    }
+
+    #[test]
+    fn rejects_missing_separator() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        assert!(macros.read_macros(&tokenizer.tokenize("#define foo bar")).is_err());
+    }
+
+    #[test]
+    fn stores_parsed_pattern_in_trie() {
+        let tokenizer = Tokenizer::default();
+        let macros = macros_from("#define let $x be $y : $y = $x");
+
+        let literal = |value: &str| PatternItem::MatchToken { value: value.to_string(), requires_boundary: false };
+        let path = vec![literal("let"), PatternItem::TokenVar, literal("be"), PatternItem::TokenVar];
+
+        let found = macros.contents.get(path).and_then(|view| view.value()).unwrap();
+
+        assert_eq!(found.pattern, "let $ be $");
+        assert_eq!(found.template, vec![
+            TemplateItem::Var { index: 1 },
+            TemplateItem::Text { data: " = ".to_string() },
+            TemplateItem::Var { index: 0 }
+        ]);
+
+        let mut macros = Macros::new();
+        let error = macros.read_macros(&tokenizer.tokenize("#define : bar")).unwrap_err();
+        assert_eq!(error.to_string(), "Macro definition has an empty pattern");
+    }
+
+    #[test]
+    fn token_output_matches_byte_output() {
+        let macros = macros_from("#define swap $a $b : $b $a\n#define call $f ( $args ) : $f[$args]");
+
+        let input = "swap x y\ncall g(1, 2) done\n";
+
+        assert_eq!(expand_via_tokens(&macros, input), expand(&macros, input));
+        assert_eq!(expand_via_tokens(&macros, input), "y x\ng[1, 2] done\n");
+    }
+
+    #[test]
+    fn applies_transforms_to_rendered_template() {
+        let macros = macros_from("#define @trim @upper shout ( $a ) $x : $a $x\n#define @lower quiet $x : HUSH $x");
+
+        assert_eq!(expand(&macros, "shout () you\n"), "YOU\n");
+        assert_eq!(expand(&macros, "shout (hey) you\n"), "HEY YOU\n");
+        assert_eq!(expand(&macros, "quiet ME\n"), "hush me\n");
+    }
+
+    #[test]
+    fn rejects_unknown_transform() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        assert!(macros.read_macros(&tokenizer.tokenize("#define @shuffle foo : bar")).is_err());
+    }
+
+    #[test]
+    fn boundary_option_requires_separator() {
+        let bounded = macros_from("#define @boundary in : IN");
+        let unbounded = macros_from("#define in : IN");
+
+        assert_eq!(expand(&bounded, "in x\n"), "IN x\n");
+        assert_eq!(expand(&bounded, "in(x)\n"), "in(x)\n");
+        assert_eq!(expand(&bounded, "x in"), "x IN");
+
+        assert_eq!(expand(&unbounded, "in(x)\n"), "IN(x)\n");
+    }
+
+    #[test]
+    fn boundary_option_needs_final_literal() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        assert!(macros.read_macros(&tokenizer.tokenize("#define @boundary in $x : IN")).is_err());
+    }
+
+    #[test]
+    fn to_dot_labels_patterns_and_templates() {
+        let macros = macros_from("#define call $f ( $args ) : $f[$args]");
+
+        let dot = macros.to_dot();
+
+        assert!(dot.contains("n0 -> n1 [label=\"call\"];"));
+        assert!(dot.contains("n1 -> n2 [label=\"$\"];"));
+        assert!(dot.contains("[label=\"( $ )\"];"));
+        assert!(dot.contains("[shape=box, label=\"$0[$1]\"];"));
+    }
+
+    #[test]
+    fn sequence_var_captures_longest_run() {
+        let macros = macros_from("#define say $words... end : print($words)");
+
+        assert_eq!(expand(&macros, "say hello (big end) world end end\n"), "print(hello (big end) world end)\n");
+
+        //The run gives back tokens until the rest of the pattern matches
+        assert_eq!(expand(&macros, "say hello end world\n"), "print(hello) world\n");
+        assert_eq!(expand(&macros, "(say hello) end\n"), "(say hello) end\n");
+    }
+
+    #[test]
+    fn sequence_var_stops_at_close_paren() {
+        let macros = macros_from("#define fn $name ( $args... ) : def $name ( $args )");
+
+        assert_eq!(expand(&macros, "fn f (a, (b) c) rest\n"), "def f ( a, (b) c ) rest\n");
+
+        //The run needs at least one token
+        assert_eq!(expand(&macros, "fn f () rest\n"), "fn f () rest\n");
+    }
+
+    #[test]
+    fn bounds_sequence_var_backtracking() {
+        let mut macros = macros_from("#define $a... $b... $c... stop : found");
+        macros.set_max_match_steps(10_000);
+
+        let tokenizer = Tokenizer::default();
+        let input = "go ".repeat(200);
+
+        assert!(macros.expand_to_tokens(&tokenizer.tokenize(&input)).is_err());
+        assert_eq!(expand(&macros, "go go go stop\n"), "found\n");
+    }
+
+    #[test]
+    fn fragments_are_inlined() {
+        let macros = macros_from("
+            #fragment TYPE : %NAME < $arg >
+            #fragment NAME : $name
+            #define let $x as %TYPE = $value : $name($arg) $x = $value
+            #define new %TYPE : $name($arg).new()
+        ");
+
+        assert_eq!(expand(&macros, "let a as Vec < u8 > = b\n"), "Vec(u8) a = b\n");
+        assert_eq!(expand(&macros, "new Box < T >\n"), "Box(T).new()\n");
+    }
+
+    #[test]
+    fn rejects_fragment_cycles() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        let definitions = "#fragment A : a %B #fragment B : b %A #define x %A : y";
+
+        assert!(macros.read_macros(&tokenizer.tokenize(definitions)).is_err());
+        assert!(macros.read_macros(&tokenizer.tokenize("#define x %C : y")).is_err());
+    }
+
+    #[test]
+    fn writes_line_directives_before_expansions() {
+        let mut macros = macros_from("#define foo $x : bar $x");
+
+        macros.set_line_directives(Some(LineDirectives {
+            format: "#line {line} \"{file}\"".to_string(),
+            file_name: "in.c".to_string()
+        }));
+
+        let output = expand(&macros, "a\nfoo 1\nb\n\nfoo\n2\n");
+
+        assert_eq!(output, "a\n#line 2 \"in.c\"\nbar 1\nb\n\n#line 5 \"in.c\"\nbar 2\n");
+    }
+
+    #[test]
+    fn line_directives_start_their_own_line() {
+        let mut macros = macros_from("#define foo : one\ntwo\n#define bar : three");
+
+        macros.set_line_directives(Some(LineDirectives {
+            format: "#line {line}".to_string(),
+            file_name: "in.c".to_string()
+        }));
+
+        //Mid-line expansions move onto a line after their directive,
+        //and text after an expansion with more lines than it replaced is resynced
+        let output = expand(&macros, "x foo y\nz bar w\nv\n");
+
+        assert_eq!(output, "x \n#line 1\none\ntwo \n#line 1\ny\nz \n#line 2\nthree w\nv\n");
+    }
+
+    #[test]
+    fn literal_index_matches_general_matcher() {
+        let definitions = "#define a b : 1 #define a c d : 2 #define e : 3 #define @upper f : four";
+        let input = "a b a c a c d e\nf a\n";
+
+        let literal = macros_from(definitions);
+        assert!(literal.is_literal_only());
+
+        let mut general = macros_from(definitions);
+        general.literal_index = None;
+
+        assert_eq!(expand(&literal, input), expand(&general, input));
+        assert_eq!(expand(&literal, input), "1 a c 2 3\nFOUR a\n");
+
+        let with_var = macros_from("#define a b : 1 #define g $x : 2");
+        assert!(!with_var.is_literal_only());
+    }
+
+    /// Timing dependent, so run it explicitly with `cargo test -- --ignored bench_literal_index`
+    #[test]
+    #[ignore]
+    fn bench_literal_index() {
+        use std::time::Instant;
+
+        let definitions: String = (0 .. 1000)
+            .map(|index| format!("#define word{} suffix{} : replaced{}\n", index, index % 7, index))
+            .collect();
+
+        let input: String = (0 .. 200_000)
+            .map(|index| format!("word{} suffix{} ", index % 1500, index % 5))
+            .collect();
+
+        let tokenizer = Tokenizer::default();
+        let tokens = tokenizer.tokenize(&input);
+
+        let literal = macros_from(&definitions);
+        let mut general = macros_from(&definitions);
+        general.literal_index = None;
+
+        let start = Instant::now();
+        let literal_output = literal.expand_to_tokens(&tokens).unwrap();
+        let literal_time = start.elapsed();
+
+        let start = Instant::now();
+        let general_output = general.expand_to_tokens(&tokens).unwrap();
+        let general_time = start.elapsed();
+
+        assert_eq!(literal_output, general_output);
+
+        assert!(literal_time < general_time, "literal index: {:?}, general matcher: {:?}", literal_time, general_time);
+    }
+
+    #[test]
+    fn prologue_and_epilogue_bracket_document() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = macros_from("#define foo : bar");
+
+        macros.set_prologue(Some("// generated\n".to_string()));
+        macros.set_epilogue(Some("// end\n".to_string()));
+
+        for (input, expected) in [("foo\n", "// generated\nbar\n// end\n"), ("", "// generated\n// end\n")].iter() {
+            let buffer = SharedBuffer::default();
+            let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+            macros.expand_document(&tokenizer.tokenize(input), &mut out_stream).unwrap();
+
+            assert_eq!(&buffer.contents(), expected);
+        }
+    }
+
+    #[test]
+    fn matches_non_ascii_tokens() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.extend_singletons("«»".chars());
+
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize("#define печать « $x » : 打印($x)")).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        macros.expand_tokens(&tokenizer.tokenize("печать «значение» печать\n"), &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "打印(значение) печать\n");
+    }
+
+    #[test]
+    fn summarizes_macro_set() {
+        let macros = macros_from("
+            #define if ( $cond ) { $body } : if $cond: $body
+            #define if let $x : let $x
+            #define print $x... ; : print($x)
+            #define pi | tau : 3
+        ");
+
+        assert_eq!(macros.stats(), MacroSetStats {
+            total: 5,
+            with_vars: 3,
+            with_blocks: 1,
+            max_pattern_len: 3,
+            distinct_first_tokens: 4
+        });
+
+        assert_eq!(macros.stats().to_string(), "macros: 5\nwith variables: 3\nwith blocks: 1\nlongest pattern: 3\ndistinct first tokens: 4\n");
+    }
+
+    #[test]
+    fn continued_template_lines_are_joined() {
+        let macros = macros_from("#define greet $x :\n    hello \\\n    $x, \\\n    how\\\n    dy\n#define end : .");
+
+        assert_eq!(expand(&macros, "greet bob\n"), "hello bob, howdy\n");
+    }
+
+    #[test]
+    fn memoizes_recursive_expansions() {
+        let mut macros = macros_from("#define twice $x : wrap ( $x $x ) #define wrap ( $x ) : [$x]");
+
+        macros.set_recursion(Some(Recursion {
+            tokenizer: Tokenizer::default(),
+            max_depth: 4
+        }));
+
+        assert_eq!(expand(&macros, "twice a twice a\n"), "[a a] [a a]\n");
+        assert_eq!(macros.memo_hits.get(), 1);
+
+        //Reused expansions still count towards usage
+        macros.set_usage_tracking(true);
+        macros.memo.borrow_mut().clear();
+
+        assert_eq!(expand(&macros, "twice a twice a\n"), "[a a] [a a]\n");
+        assert_eq!(macros.memo_hits.get(), 2);
+        assert_eq!(macros.usage_report(), "2\ttwice $\n2\twrap ( $ )\n");
+
+        let mut macros = macros_from("#define loop : loop");
+
+        macros.set_recursion(Some(Recursion {
+            tokenizer: Tokenizer::default(),
+            max_depth: 4
+        }));
+
+        assert!(macros.expand_to_tokens(&Tokenizer::default().tokenize("loop")).is_err());
+    }
+
+    #[test]
+    fn bounds_mutual_recursion() {
+        let mut macros = macros_from("#define ping $x : pong $x #define pong $x : ping $x");
+
+        macros.set_recursion(Some(Recursion {
+            tokenizer: Tokenizer::default(),
+            max_depth: 5
+        }));
+
+        let error = macros.expand_to_tokens(&Tokenizer::default().tokenize("ping a")).err().unwrap();
+        assert_eq!(error.to_string(), "Recursive expansion of `ping $` exceeded the maximum depth of 5");
+    }
+
+    #[test]
+    fn deep_nesting_does_not_overflow() {
+        let tokenizer = Tokenizer::default();
+        let macros = macros_from("#define unwrap ( $x ) : $x");
+
+        let depth = 500;
+        let input = format!("unwrap {}x{}\n", "( ".repeat(depth), " )".repeat(depth));
+        let expected = format!("{}x{}\n", "( ".repeat(depth - 1), " )".repeat(depth - 1));
+
+        assert_eq!(expand(&macros, &input), expected);
+
+        let mut macros = macros_from("#define loop : loop");
+
+        macros.set_recursion(Some(Recursion {
+            tokenizer: Tokenizer::default(),
+            max_depth: usize::MAX
+        }));
+
+        assert!(macros.expand_to_tokens(&tokenizer.tokenize("loop")).is_err());
+    }
+
+    #[test]
+    fn interpolates_context_vars() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        let mut context = HashMap::new();
+        context.insert("VERSION".to_string(), "1.2.3".to_string());
+        macros.set_context(context);
+
+        macros.read_macros(&tokenizer.tokenize("#define version : v = $$VERSION #define mode : $$MODE=debug")).unwrap();
+        assert_eq!(expand(&macros, "version mode\n"), "v = 1.2.3 debug\n");
+
+        assert!(macros.read_macros(&tokenizer.tokenize("#define other : $$OTHER")).is_err());
+    }
+
+    #[test]
+    fn reports_usage_across_documents() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = macros_from("#define foo : 1 #define bar $x : 2 #define baz : 3");
+        macros.set_usage_tracking(true);
+
+        let first = tokenizer.tokenize("foo bar a foo");
+        let second = tokenizer.tokenize("bar b foo");
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+        macros.expand_documents(&[(None, &first), (None, &second)], "", &mut out_stream).unwrap();
+
+        assert_eq!(macros.usage_report(), "3\tfoo\n2\tbar $\n");
+    }
+
+    #[test]
+    fn aborts_after_timeout() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = macros_from("#define foo $x : bar $x");
+        let input = "foo x ".repeat(10_000);
+
+        macros.set_timeout(Some(Duration::from_secs(60)));
+        assert!(macros.expand_to_tokens(&tokenizer.tokenize(&input)).is_ok());
+
+        macros.set_timeout(Some(Duration::from_nanos(0)));
+        let error = macros.expand_to_tokens(&tokenizer.tokenize(&input)).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn block_delimiter_mismatch_depends_on_strictness() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = macros_from("#define call ( $args ) : invoke($args)");
+
+        assert_eq!(expand(&macros, "call [x]\n"), "call [x]\n");
+
+        macros.set_strict_block_delimiters(true);
+
+        assert_eq!(expand(&macros, "call (x)\n"), "invoke(x)\n");
+
+        let error = macros.expand_to_tokens(&tokenizer.tokenize("call [x]\n")).unwrap_err();
+        assert_eq!(error.to_string(), "Expected `(` here, found `[`");
+    }
+
+    #[test]
+    fn back_references_respect_case_sensitivity() {
+        let tokenizer = Tokenizer::default();
+        let definitions = "#define LET $x = $x : same $x";
+
+        let macros = macros_from(definitions);
+
+        assert_eq!(expand(&macros, "LET a = a\n"), "same a\n");
+        assert_eq!(expand(&macros, "LET a = b\n"), "LET a = b\n");
+        assert_eq!(expand(&macros, "LET X = x\n"), "LET X = x\n");
+        assert_eq!(expand(&macros, "let a = a\n"), "let a = a\n");
+
+        let mut macros = Macros::new();
+        macros.set_case_insensitive(true);
+        macros.read_macros(&tokenizer.tokenize(definitions)).unwrap();
+
+        //The binding keeps the form of its first occurrence
+        assert_eq!(expand(&macros, "let X = x\n"), "same X\n");
+        assert_eq!(expand(&macros, "Let x = X\n"), "same x\n");
+        assert_eq!(expand(&macros, "let a = b\n"), "let a = b\n");
+
+        let mut macros = Macros::new();
+        let error = macros.read_macros(&tokenizer.tokenize("#define pair ( $x ) $x : $x")).unwrap_err();
+        assert_eq!(error.to_string(), "Only token variables can be referred back to, not `$x`");
+    }
+
+    #[test]
+    fn describes_overridden_settings() {
+        let mut macros = macros_from("#define foo : bar");
+
+        assert!(macros.describe_settings().contains("recursion depth: off\n"));
+
+        macros.set_recursion(Some(Recursion {
+            tokenizer: Tokenizer::default(),
+            max_depth: 3
+        }));
+        macros.set_timeout(Some(Duration::from_millis(250)));
+        macros.set_case_insensitive(true);
+
+        let mut features = HashSet::new();
+        features.insert("b".to_string());
+        features.insert("a".to_string());
+        macros.set_features(features);
+
+        let settings = macros.describe_settings();
+
+        assert!(settings.starts_with("macros: 1\nrecursion depth: 3\ntimeout: 250ms\n"));
+        assert!(settings.contains("ignore case: true\n"));
+        assert!(settings.contains("features: a b\n"));
+    }
+
+    #[test]
+    fn writes_to_side_outputs() {
+        let macros = macros_from("#define fn $name ( $arg ) : $to header { void $name ( $arg ) ; } void $name ( $arg ) { } #define end : done");
+
+        assert_eq!(expand(&macros, "fn f ( x )\nfn g ( y )\nend\n"), "void f ( x ) { }\nvoid g ( y ) { }\ndone\n");
+
+        let side_outputs = macros.take_side_outputs();
+
+        assert_eq!(side_outputs.len(), 1);
+        assert_eq!(side_outputs["header"], "void f ( x ) ;\nvoid g ( y ) ;\n");
+        assert!(macros.take_side_outputs().is_empty());
+    }
+
+    #[test]
+    fn reads_back_to_back_definitions() {
+        let macros = macros_from("#define foo $x : bar $x #define baz : qux");
+
+        let literal = |value: &str| PatternItem::MatchToken { value: value.to_string(), requires_boundary: false };
+
+        let foo = macros.contents.get(vec![literal("foo"), PatternItem::TokenVar]).and_then(|view| view.value()).unwrap();
+        let baz = macros.contents.get(vec![literal("baz")]).and_then(|view| view.value()).unwrap();
+
+        assert_eq!(describe_template(&foo.template), "bar $0");
+        assert_eq!(describe_template(&baz.template), "qux");
+    }
+
+    #[test]
+    fn reports_stray_tokens_in_macrofiles() {
+        let tokenizer = Tokenizer::default();
+
+        let mut macros = Macros::new();
+        let error = macros.read_macros(&tokenizer.tokenize("\n\n  junk #define foo : bar")).unwrap_err();
+        assert_eq!(error.to_string(), "Expected `#define` or `#fragment` on line 3, column 3 but found `junk`");
+
+        //Near misses of a header only warn and are kept as template text
+        let macros = macros_from("#define foo : bar\n#defne baz : qux\n");
+        assert_eq!(expand(&macros, "foo"), "bar\n#defne baz : qux");
+
+        let macros = macros_from("#define check : x is\n#defined y\n");
+        assert_eq!(expand(&macros, "check"), "x is\n#defined y");
+
+        //Other directives in templates are left alone
+        let macros = macros_from("#define header :\n#include <a.h>\n#pragma once\n");
+        assert_eq!(expand(&macros, "header"), "#include <a.h>\n#pragma once");
+    }
+
+    #[test]
+    fn dumps_bindings_for_debugging() {
+        let macros = macros_from("#define call $f ( $args ) : $debug $f ( $args )");
+
+        assert_eq!(expand(&macros, "call g (1, 2)\n"), "[debug: $f = `g`, $args = `1, 2`] g ( 1, 2 )\n");
+
+        //A variable of the same name takes precedence
+        let macros = macros_from("#define show $debug : [ $debug ]");
+
+        assert_eq!(expand(&macros, "show x\n"), "[ x ]\n");
+    }
+
+    #[test]
+    fn renders_for_each_over_sequence() {
+        let macros = macros_from("#define list [ $xs... ] : $for $xs ( , ) { $.n: $. } ; #define each $x : $for $x { < $. > }");
+
+        assert_eq!(expand(&macros, "list [a b c]\n"), "1: a, 2: b, 3: c ;\n");
+        assert_eq!(expand(&macros, "each x\n"), "< x >\n");
+    }
+
+    #[test]
+    fn parses_nested_blocks() {
+        let tokenizer = Tokenizer::default();
+
+        fn values<'a>(tokens: &[Token<'a>]) -> Vec<&'a str> {
+            tokens.iter().map(|token| token.value).collect()
+        }
+
+        let tokens = tokenizer.tokenize("{ a [ b ] { c } } d");
+        let BlockParse { block_tokens, remaining } = parse_block(&tokens[1 .. ], &BlockDelimiter::CurlyBracket).unwrap();
+
+        assert_eq!(values(block_tokens), vec!["a", "[", "b", "]", "{", "c", "}"]);
+        assert_eq!(values(remaining), vec!["d"]);
+
+        let tokens = tokenizer.tokenize("{ [ } ]");
+        let error = parse_block(&tokens[1 .. ], &BlockDelimiter::CurlyBracket).err().unwrap();
+        assert_eq!(error.to_string(), "Unbalanced `}`");
+
+        let tokens = tokenizer.tokenize("{ [ ]");
+        let error = parse_block(&tokens[1 .. ], &BlockDelimiter::CurlyBracket).err().unwrap();
+        assert_eq!(error.to_string(), "Missing `}`");
+    }
+
+    #[test]
+    fn splits_at_top_level_separators() {
+        let tokenizer = Tokenizer::default();
+        let tokens = tokenizer.tokenize("a, (b, c), d");
+
+        let parts: Vec<Vec<&str>> = split_top_level(&tokens, ",").iter()
+            .map(|part| part.iter().map(|token| token.value).collect())
+            .collect();
+
+        assert_eq!(parts, vec![vec!["a"], vec!["(", "b", ",", "c", ")"], vec!["d"]]);
+        assert_eq!(split_top_level(&tokens[ .. 0], ",").len(), 1);
+    }
+
+    #[test]
+    fn guarded_definitions_need_their_feature() {
+        let tokenizer = Tokenizer::default();
+        let definitions = "#[if feature=debug] define trace : println() #define x : y";
+
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize(definitions)).unwrap();
+        assert_eq!(expand(&macros, "trace x\n"), "trace y\n");
+
+        let mut features = HashSet::new();
+        features.insert("debug".to_string());
+
+        let mut macros = Macros::new();
+        macros.set_features(features);
+        macros.read_macros(&tokenizer.tokenize(definitions)).unwrap();
+        assert_eq!(expand(&macros, "trace x\n"), "println() y\n");
+    }
+
+    #[test]
+    fn estimates_bound_output_len() {
+        let tokenizer = Tokenizer::default();
+        let macros = macros_from("
+            #define if ( $cond ) { $body } : if $cond:\n    $body
+            #define list [ $xs... ] : $for $xs ( , ) { $.n: $. }
+            #define pi : 3.14159
+        ");
+
+        for input in ["if (a == b) { run(); }\n", "list [a bb ccc] pi\n", "nothing to expand\n", ""].iter() {
+            let tokens = tokenizer.tokenize(input);
+
+            assert!(macros.estimate_output_len(&tokens) >= expand(&macros, input).len(), "Underestimated {:?}", input);
+        }
+
+        let literal_only = macros_from("#define pi : 3.14159");
+        assert_eq!(literal_only.estimate_output_len(&tokenizer.tokenize("pi and pi\n")), expand(&literal_only, "pi and pi\n").len());
+    }
+
+    #[test]
+    fn recovers_from_broken_definitions() {
+        let tokenizer = Tokenizer::default();
+        let definitions = "#define foo : bar\n#define broken $x\n#define baz : qux\n";
+
+        let mut macros = Macros::new();
+        assert!(macros.read_macros(&tokenizer.tokenize(definitions)).is_err());
+
+        let mut macros = Macros::new();
+        macros.set_recover_syntax_errors(true);
+        macros.read_macros(&tokenizer.tokenize(definitions)).unwrap();
+
+        assert_eq!(expand(&macros, "foo broken baz\n"), "bar broken qux\n");
+    }
+
+    #[test]
+    fn recovering_skips_whole_definitions_but_not_conflicts() {
+        let tokenizer = Tokenizer::default();
+
+        let mut macros = Macros::new();
+        macros.set_recover_syntax_errors(true);
+        macros.read_macros(&tokenizer.tokenize("#define foo | bar %missing : baz\n#define qux : quux")).unwrap();
+
+        assert_eq!(macros.pattern_count(), 1);
+        assert_eq!(expand(&macros, "foo qux"), "foo quux");
+
+        let conflicting = macros.read_macros(&tokenizer.tokenize("#define qux : again"));
+        assert_eq!(conflicting.unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn captures_until_delimiter() {
+        let macros = macros_from("
+            #define loop $body~end end : while true { $body }
+            #define skip $rest~; : ()
+        ");
+
+        assert_eq!(expand(&macros, "loop a (b end) c end\n"), "while true { a (b end) c }\n");
+        assert_eq!(expand(&macros, "skip a b ; c\n"), "() ; c\n");
+        assert_eq!(expand(&macros, "(skip a) b ;\n"), "(skip a) b ;\n");
+
+        //An empty capture would consume nothing, so it never matches
+        let macros = macros_from("#define $x~; : X");
+        assert_eq!(expand(&macros, "; a ;\n"), "; X ;\n");
+
+        let tokenizer = Tokenizer::default();
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        macros.expand_side_by_side(&tokenizer.tokenize("; a"), None, &mut out_stream).unwrap();
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn joins_documents_with_separator() {
+        let tokenizer = Tokenizer::default();
+        let macros = macros_from("#define foo : bar");
+
+        let inputs: Vec<_> = ["foo 1", "2", "foo 3"].iter()
+            .map(|input| tokenizer.tokenize(input))
+            .collect();
+
+        let documents: Vec<_> = inputs.iter()
+            .map(|tokens| (None, tokens.as_slice()))
+            .collect();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        macros.expand_documents(&documents, "\n--\n", &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "bar 1\n--\n2\n--\nbar 3");
+    }
+
+    #[test]
+    fn matches_do_not_span_documents() {
+        let tokenizer = Tokenizer::default();
+        let macros = macros_from("#define foo bar : baz");
+
+        let first = tokenizer.tokenize("a foo");
+        let second = tokenizer.tokenize("bar foo bar");
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        macros.expand_documents(&[(None, &first), (None, &second)], " ", &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "a foo bar baz");
+    }
+
+    #[test]
+    fn lookahead_is_not_consumed() {
+        let macros = macros_from("#define foo ?= ( : call #define let $x ?= = $y : let $x: $y");
+
+        assert_eq!(expand(&macros, "foo(x) foo x\n"), "call(x) foo x\n");
+        assert_eq!(expand(&macros, "let a = b\n"), "let a: b = b\n");
+    }
+
+    #[test]
+    fn rejects_misplaced_lookahead() {
+        let tokenizer = Tokenizer::default();
+
+        for definitions in ["#define ?= ( : x", "#define foo ?= : x", "#define a ?= b ?= c : x"].iter() {
+            let mut macros = Macros::new();
+
+            assert!(macros.read_macros(&tokenizer.tokenize(definitions)).is_err());
+        }
+    }
+
+    #[test]
+    fn alternatives_share_template() {
+        let macros = macros_from("#define $a plus $b | add $b to $a : $a + $b");
+
+        assert_eq!(expand(&macros, "1 plus 2\n"), "1 + 2\n");
+        assert_eq!(expand(&macros, "add 2 to 1\n"), "1 + 2\n");
+    }
+
+    #[test]
+    fn rejects_conflicting_alternatives() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+
+        assert!(macros.read_macros(&tokenizer.tokenize("#define foo | foo bar : baz")).is_err());
+        assert!(macros.read_macros(&tokenizer.tokenize("#define qux | : baz")).is_err());
+    }
+
+    #[test]
+    fn failed_alternatives_insert_nothing() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = macros_from("#define foo : bar");
+
+        let conflicting = macros.read_macros(&tokenizer.tokenize("#define qux | foo : baz"));
+        assert_eq!(conflicting.unwrap_err().to_string(), "Macro pattern `foo` on line 1 conflicts with `foo` on line 1 of an earlier source");
+
+        let unparsable = macros.read_macros(&tokenizer.tokenize("#define quux | %missing : baz"));
+        assert!(unparsable.is_err());
+
+        assert_eq!(macros.pattern_count(), 1);
+        assert_eq!(expand(&macros, "qux quux foo"), "qux quux bar");
+    }
+
+    #[test]
+    fn dumps_macros() {
+        let macros = macros_from("#define @trim foo $x ( $y ) :  bar $x \n#define baz : qux");
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+        macros.dump(&mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "\
+            source 0 line 1: foo $ ( $ )\n\
+            \x20 pattern: [MatchToken { value: \"foo\", requires_boundary: false }, TokenVar, BlockVar { block_delim: Parenthesis }]\n\
+            \x20 template: \"bar $0\"\n\
+            \x20 transforms: [Trim]\n\
+            source 0 line 2: baz\n\
+            \x20 pattern: [MatchToken { value: \"baz\", requires_boundary: false }]\n\
+            \x20 template: \"qux\"\n");
+    }
+
+    #[test]
+    fn reports_both_conflicting_patterns() {
+        let tokenizer = Tokenizer::default();
+        let error_for = |definitions: &str| {
+            Macros::new().read_macros(&tokenizer.tokenize(definitions)).err().unwrap().to_string()
+        };
+
+        assert_eq!(error_for("#define foo : 1\n#define foo bar : 2"), "Macro pattern `foo bar` on line 2 conflicts with `foo` on line 1");
+        assert_eq!(error_for("#define foo bar : 1\n\n#define foo : 2"), "Macro pattern `foo` on line 3 conflicts with `foo bar` on line 1");
+        assert_eq!(error_for("#define foo $x : 1\n#define foo $y : 2"), "Macro pattern `foo $` on line 2 conflicts with `foo $` on line 1");
+
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize("#define foo : 1")).unwrap();
+
+        let error = macros.read_macros(&tokenizer.tokenize("#define foo bar : 2")).err().unwrap();
+        assert_eq!(error.to_string(), "Macro pattern `foo bar` on line 1 conflicts with `foo` on line 1 of an earlier source");
+    }
+
+    fn macros_with_broken_template() -> Macros {
+        //Only the first alternative binds $x, so the template fails to render for the second
+        macros_from("#define wrap ( $x ) | wrap nothing : [$x]")
+    }
+
+    #[test]
+    fn back_references_match_only_equal_tokens() {
+        let macros = macros_from("#define let $x = $x : $x is itself");
+
+        assert_eq!(expand(&macros, "let a = a\n"), "a is itself\n");
+        assert_eq!(expand(&macros, "let a = b\n"), "let a = b\n");
+
+        //Without ignoring case, tokens differing only in case are not equal
+        assert_eq!(expand(&macros, "let a = A\n"), "let a = A\n");
+
+        //Only a variable capturing a single token can be referred back to
+        let tokenizer = Tokenizer::default();
+        let error = Macros::new().read_macros(&tokenizer.tokenize("#define same ( $x ) $x : matched")).unwrap_err();
+        assert_eq!(error.to_string(), "Only token variables can be referred back to, not `$x`");
+    }
+
+    #[test]
+    fn templates_drop_escapes() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.set_escape_char(Some('\\'));
+
+        let mut macros = Macros::new();
+        macros.set_escape_char(tokenizer.escape_char());
+        macros.read_macros(&tokenizer.tokenize("#define foo $x : \\{ $x \\: \\$x \\\\ \\}")).unwrap();
+
+        let tokens = tokenizer.tokenize("foo a");
+        let output: String = macros.expand_to_tokens(&tokens).unwrap()
+            .iter()
+            .map(|token| format!("{}{}", token.value, token.suffix))
+            .collect();
+
+        assert_eq!(output, "{ a : $x \\ }");
+    }
+
+    #[test]
+    fn passes_unmatched_text_through_exactly() {
+        let macros = macros_from("#define foo : bar");
+        let input = "  lead\tand  (tabs\t\t)\r\n\n   trail \t";
+
+        assert_eq!(expand(&macros, input), input);
+        assert_eq!(expand(&macros, "a \t foo\t\t b  \n"), "a \t bar\t\t b  \n");
+    }
+
+    #[test]
+    fn render_failure_aborts_by_default() {
+        let macros = macros_with_broken_template();
+        let tokenizer = Tokenizer::default();
+
+        assert_eq!(expand(&macros, "a wrap (x) b\n"), "a [x] b\n");
+
+        let error = macros.expand_to_tokens(&tokenizer.tokenize("a wrap  nothing b\n")).err().unwrap();
+        assert_eq!(error.to_string(), "Template variable `$x` is not bound in the template of `wrap nothing`");
+
+        //A variable bound by no alternative is rejected when read
+        let error = Macros::new().read_macros(&tokenizer.tokenize("#define wrap ( $x ) | wrap nothing : [$y]")).unwrap_err();
+        assert_eq!(error.to_string(), "Template uses unbound variable `$y`");
+    }
+
+    #[test]
+    fn render_failure_can_pass_through() {
+        let mut macros = macros_with_broken_template();
+        macros.set_render_error_policy(RenderErrorPolicy::PassthroughWarn);
+
+        assert_eq!(expand(&macros, "a wrap  nothing b wrap (x)\n"), "a wrap  nothing b [x]\n");
+    }
+
+    #[test]
+    fn expands_only_within_range() {
+        let macros = macros_from("#define foo : bar");
+        let tokenizer = Tokenizer::default();
+
+        let expand_range = |start_byte, end_byte| {
+            let buffer = SharedBuffer::default();
+            let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+            macros.expand_range(&tokenizer.tokenize("foo foo foo\n"), start_byte, end_byte, &mut out_stream).unwrap();
+
+            buffer.contents()
+        };
+
+        assert_eq!(expand_range(4, 8), "foo bar foo\n");
+        assert_eq!(expand_range(4, 12), "foo bar bar\n");
+        assert_eq!(expand_range(2, 6), "foo foo foo\n");
+        assert_eq!(expand_range(0, 0), "foo foo foo\n");
+    }
+
+    #[test]
+    fn writes_expansions_side_by_side() {
+        let macros = macros_from("#define add $x to $y : $y += $x ;");
+        let tokenizer = Tokenizer::default();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        macros.expand_side_by_side(&tokenizer.tokenize("start\nadd 1 to x\nend\n"), None, &mut out_stream).unwrap();
+        macros.expand_side_by_side(&tokenizer.tokenize("add 2 to y\n"), Some("b.txt"), &mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "@@ line 2 @@\n- add 1 to x\n+ x += 1 ;\n@@ b.txt line 1 @@\n- add 2 to y\n+ y += 2 ;\n");
+    }
+
+    #[test]
+    fn renders_placeholders_for_unbound_vars() {
+        let tokenizer = Tokenizer::default();
+        let definitions = tokenizer.tokenize("#define partial $x : $x and $y $for $x { $. $z }");
+
+        let error = Macros::new().read_macros(&definitions).unwrap_err();
+        assert_eq!(error.to_string(), "Template uses unbound variable `$y`");
+
+        let mut macros = Macros::new();
+        macros.set_unbound_placeholders(true);
+        macros.read_macros(&definitions).unwrap();
+
+        assert_eq!(expand(&macros, "partial x\n"), "x and <?y> x <?z>\n");
+    }
+
+    #[test]
+    fn coalesces_adjacent_text() {
+        let tokenizer = Tokenizer::default();
+        let tokens = tokenizer.tokenize("a b $x c d");
+        let var_names = vec!["x".to_string()];
+
+        let template = parse_template(&tokens, &var_names, &HashMap::new()).unwrap();
+        let coalesced = coalesce_text(template.clone());
+
+        assert_eq!(template.len(), 6);
+        assert_eq!(coalesced, vec![
+            TemplateItem::Text { data: "a b ".to_string() },
+            TemplateItem::Var { index: 0 },
+            TemplateItem::Text { data: " c d".to_string() }
+        ]);
+
+        let bindings = [&tokens[2 .. 3]];
+        let context = HashMap::new();
+
+        assert_eq!(
+            render(&template, &bindings, false, &context, &mut Vec::new()).unwrap(),
+            render(&coalesced, &bindings, false, &context, &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn passthrough_tokens_are_borrowed() {
+        let macros = macros_from("#define foo : bar");
+        let tokenizer = Tokenizer::default();
+
+        let output = macros.expand_to_tokens(&tokenizer.tokenize("foo baz\n")).unwrap();
+
+        assert!(match output[0].value { Cow::Owned(_) => true, Cow::Borrowed(_) => false });
+        assert!(match output[1].value { Cow::Owned(_) => false, Cow::Borrowed(_) => true });
+        assert_eq!(output[1], ExpandedToken::from(&Token::new("baz", "\n")));
+    }
 }
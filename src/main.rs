@@ -14,10 +14,9 @@
  * ### C-style if to Python-style
  * Pattern
  * ```
- * #define if ( $cond ) { $block }
+ * #define if ( $cond ) { $block } :
  * if $cond:
  *   $block
- * #end
  * ```
  * 
  * Input
@@ -41,93 +40,753 @@ mod tokenizer;
 use tokenizer::Tokenizer;
 
 mod io_helpers;
-use io_helpers::{ simplify_output, file_to_string, stdio_to_string };
+use io_helpers::{ simplify_output, buffer_output, create_output, file_to_string, stdio_to_string };
+
+mod stream;
+use stream::StreamExpander;
 
 mod builder;
-use builder::build_macros;
+use builder::{ build_macros, read_manifest, split_combined };
+
+use macro_def::{ Macros, LineDirectives, Recursion, ConflictPolicy, RenderErrorPolicy, MAX_RECURSION_DEPTH };
 
+use std::collections::{ HashMap, HashSet };
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{ Write, Result, Error, ErrorKind, stdout };
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use std::io::{ BufRead, BufReader, Write, Result, Error, ErrorKind, stdin, stdout };
 
 extern crate clap;
 use clap::{ Arg, App };
 
 fn main() {
-    if let Err(error) = run_command() {
-        println!("{}", error);
+    //A nonzero status lets --check, and scripts in general, tell that slang failed
+    if let Err(error) = get_task(std::env::args_os()).and_then(run_command) {
+        eprintln!("{}", error);
+        std::process::exit(1);
     }
 }
 
-fn run_command() -> Result<()> {
-    let task = get_task()?;
+/// The file name used for stdin in line directives
+const STDIN_NAME: &str = "<stdin>";
 
-    let tokenizer = Tokenizer::default();
+fn run_command(task: Task) -> Result<()> {
+    let mut tokenizer = match &task.tokenizer_config {
+        Some(config_file) => Tokenizer::from_config_file(Path::new(config_file))?,
+        None => Tokenizer::default()
+    };
 
-    let macro_defs = build_macros(&tokenizer, task.macro_files)?;
+    if let Some(singletons) = &task.singletons {
+        tokenizer.extend_singletons(singletons.chars());
+    }
 
-    let input = match task.in_file {
-        Some(in_file) => file_to_string(File::open(in_file)?)?,
-        None => stdio_to_string()?
+    if let Some(separators) = &task.separators {
+        tokenizer.extend_separators(separators.chars());
+    }
+
+    if let Some(quotes) = &task.quotes {
+        tokenizer.extend_quote_chars(quotes.chars());
+    }
+
+    if let Some(escape) = task.escape {
+        tokenizer.set_escape_char(Some(escape));
+    }
+
+    let mut macro_files = match task.macro_manifest {
+        Some(manifest_file) => read_manifest(Path::new(&manifest_file))?,
+        None => Vec::new()
     };
 
-    let out_stream: Box<Write> = match task.out_file {
-        Some(out_file) => Box::new(File::create(out_file)?),
-        None => Box::new(stdout())
+    macro_files.extend(task.macro_files.iter().map(PathBuf::from));
+
+    if macro_files.is_empty() && task.defines.is_empty() && !task.combined {
+        return Err(Error::new(ErrorKind::InvalidInput, "Must include at least one macrofile"));
+    }
+
+    let mut macro_defs = Macros::new();
+    macro_defs.set_context(task.context);
+    macro_defs.set_features(task.features);
+    macro_defs.set_recover_syntax_errors(task.recover);
+    macro_defs.set_unbound_placeholders(task.placeholders);
+    macro_defs.set_case_insensitive(task.ignore_case);
+    macro_defs.set_escape_char(tokenizer.escape_char());
+    macro_defs.set_conflict_policy(task.on_conflict);
+    macro_defs.set_render_error_policy(task.on_render_error);
+
+    for definition in &task.defines {
+        macro_defs.read_macros(&tokenizer.tokenize(&format!("#define {}", definition)))
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, format!("Invalid --define `{}`: {}", definition, error)))?;
+    }
+
+    let mut macro_defs = build_macros(&tokenizer, macro_files, macro_defs)?;
+
+    if task.check {
+        if task.combined {
+            return Err(Error::new(ErrorKind::InvalidInput, "--check does not read the input, so cannot check --combined definitions"));
+        }
+
+        //Begins with the count reported by --show-config
+        print!("{}", macro_defs.stats());
+
+        return Ok(());
+    }
+
+    if let Some(format) = task.line_directives {
+        macro_defs.set_line_directives(Some(LineDirectives {
+            format,
+            file_name: STDIN_NAME.to_string()
+        }));
+    }
+
+    if let Some(max_depth) = task.recursive {
+        macro_defs.set_recursion(Some(Recursion {
+            tokenizer: tokenizer.clone(),
+            max_depth
+        }));
+    }
+
+    macro_defs.set_timeout(task.timeout);
+    macro_defs.set_strict_block_delimiters(task.strict_blocks);
+    macro_defs.set_prologue(task.prologue);
+    macro_defs.set_epilogue(task.epilogue);
+
+    if task.usage_report.is_some() {
+        macro_defs.set_usage_tracking(true);
+    }
+
+    if task.show_config {
+        print!("{}{}", describe_tokenizer(&tokenizer), macro_defs.describe_settings());
+
+        return Ok(());
+    }
+
+    if task.dump_macros {
+        let mut out_stream = simplify_output(Box::new(stdout()));
+        macro_defs.dump(&mut out_stream)?;
+
+        return out_stream.flush();
+    }
+
+    if task.dump_dot {
+        print!("{}", macro_defs.to_dot());
+
+        return Ok(());
+    }
+
+    let in_files = task.in_files;
+
+    if task.combined && in_files.len() > 1 {
+        return Err(Error::new(ErrorKind::InvalidInput, "--combined requires a single input"));
+    }
+
+    if task.range.is_some() && (task.stream || task.side_by_side || in_files.len() > 1) {
+        return Err(Error::new(ErrorKind::InvalidInput, "--range requires a single input, without --stream or --side-by-side"));
+    }
+
+    let in_paths: Vec<&Path> = in_files.iter().map(Path::new).collect();
+
+    if task.stream {
+        if task.combined || task.side_by_side || in_files.len() > 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "--stream requires a single input, without --combined or --side-by-side"));
+        }
+
+        let reader: Box<dyn BufRead> = match in_files.first() {
+            Some(in_file) => Box::new(BufReader::new(File::open(in_file)?)),
+            None => Box::new(BufReader::new(stdin()))
+        };
+
+        let (out_stream, temp_path) = open_output(&task.out_file, &in_paths, task.overwrite_input, task.buffer_size)?;
+
+        stream_expand(&macro_defs, &tokenizer, reader, out_stream)?;
+
+        return finish_expansion(&macro_defs, &task.side_outputs, temp_path, &task.out_file, &task.usage_report);
+    }
+
+    let mut inputs = Vec::new();
+
+    for in_file in &in_files {
+        inputs.push(file_to_string(File::open(in_file)?)?);
+    }
+
+    if inputs.is_empty() {
+        inputs.push(stdio_to_string()?);
+    }
+
+    let input_sections: Vec<&str> = if task.combined {
+        let (macro_section, input_section) = split_combined(&inputs[0])?;
+
+        macro_defs.read_macros(&tokenizer.tokenize(macro_section))?;
+
+        vec![input_section]
+    } else {
+        inputs.iter().map(String::as_str).collect()
     };
 
-    macro_defs.expand_tokens(
-        &tokenizer.tokenize(&input), 
-        &mut simplify_output(out_stream))
+    let input_tokens: Vec<_> = input_sections.iter()
+        .map(|section| tokenizer.tokenize(section))
+        .collect();
+
+    //Without any input files, the single document comes from stdin and is left unnamed
+    let documents: Vec<_> = input_tokens.iter()
+        .enumerate()
+        .map(|(index, tokens)| (in_files.get(index).map(String::as_str), tokens.as_slice()))
+        .collect();
+
+    let (out_stream, temp_path) = open_output(&task.out_file, &in_paths, task.overwrite_input, task.buffer_size)?;
+    let mut out_stream = simplify_output(out_stream);
+
+    if let Some((start_byte, end_byte)) = task.range {
+        macro_defs.expand_range(documents[0].1, start_byte, end_byte, &mut out_stream)?;
+    } else if task.side_by_side {
+        for (document_name, tokens) in &documents {
+            macro_defs.expand_side_by_side(tokens, *document_name, &mut out_stream)?;
+        }
+    } else {
+        macro_defs.expand_documents(&documents, &task.join, &mut out_stream)?;
+    }
+
+    out_stream.flush()?;
+
+    finish_expansion(&macro_defs, &task.side_outputs, temp_path, &task.out_file, &task.usage_report)
 } 
 
+/// Writes everything besides the output once it is complete
+fn finish_expansion(macro_defs: &Macros, side_outputs: &HashMap<String, String>, temp_path: Option<PathBuf>, out_file: &Option<String>, usage_report: &Option<String>) -> Result<()> {
+    write_side_outputs(macro_defs, side_outputs)?;
+    finish_output(temp_path, out_file)?;
+
+    if let Some(usage_report) = usage_report {
+        std::fs::write(usage_report, macro_defs.usage_report())?;
+    }
+
+    Ok(())
+}
+
+/// Describes every setting of the tokenizer, with characters and strings quoted so that whitespace is visible
+fn describe_tokenizer(tokenizer: &Tokenizer) -> String {
+    let describe = |chars: &HashSet<char>| {
+        let mut chars: Vec<char> = chars.iter().cloned().collect();
+        chars.sort();
+
+        chars.iter().map(|c| format!("{:?}", c)).collect::<Vec<String>>().join(" ")
+    };
+
+    let multi_singletons: Vec<String> = tokenizer.multi_singletons().iter()
+        .map(|singleton| format!("{:?}", singleton))
+        .collect();
+
+    format!("singletons: {}\nmulti_singletons: {}\nseparators: {}\nquotes: {}\nescape: {}\nline_comment: {}\ndrop_comments: {}\n",
+        describe(tokenizer.singletons()),
+        multi_singletons.join(" "),
+        describe(tokenizer.separators()),
+        describe(tokenizer.quote_chars()),
+        tokenizer.escape_char().map_or("none".to_string(), |c| format!("{:?}", c)),
+        tokenizer.line_comment().map_or("none".to_string(), |prefix| format!("{:?}", prefix)),
+        tokenizer.drop_comments())
+}
+
+/// Opens the output file, or stdout without one, buffering buffer_size bytes of it,
+/// also returning the temporary file written instead when replacing an input
+fn open_output(out_file: &Option<String>, in_paths: &[&Path], overwrite_input: bool, buffer_size: usize) -> Result<(Box<dyn Write>, Option<PathBuf>)> {
+    match out_file {
+        Some(out_file) => {
+            let (file, temp_path) = create_output(Path::new(out_file), in_paths, overwrite_input)?;
+
+            Ok((buffer_output(Box::new(file), buffer_size), temp_path))
+        },
+        None => Ok((buffer_output(Box::new(stdout()), buffer_size), None))
+    }
+}
+
+/// The output only replaces an input once it is complete
+fn finish_output(temp_path: Option<PathBuf>, out_file: &Option<String>) -> Result<()> {
+    if let (Some(temp_path), Some(out_file)) = (temp_path, out_file) {
+        std::fs::rename(temp_path, out_file)?;
+    }
+
+    Ok(())
+}
+
+/// Writes each side output to its file, discarding those without one with a warning
+fn write_side_outputs(macro_defs: &Macros, paths: &HashMap<String, String>) -> Result<()> {
+    for (name, contents) in macro_defs.take_side_outputs() {
+        match paths.get(&name) {
+            Some(path) => std::fs::write(path, contents)?,
+            None => eprintln!("Warning: no --side-output given for `{}`, discarding it", name)
+        }
+    }
+
+    Ok(())
+}
+
+/// Expands the input line by line as it is read, between the prologue and epilogue
+fn stream_expand(macro_defs: &Macros, tokenizer: &Tokenizer, mut reader: Box<dyn BufRead>, out_stream: Box<dyn Write>) -> Result<()> {
+    let mut out_stream = simplify_output(out_stream);
+    let mut expander = StreamExpander::new(macro_defs, tokenizer)?;
+    let mut line = String::new();
+
+    if let Some(prologue) = macro_defs.prologue() {
+        out_stream.write(prologue)?;
+    }
+
+    //Each line's output is flushed, so that it appears as soon as the line is read
+    while reader.read_line(&mut line)? > 0 {
+        expander.push(&line, &mut out_stream)?;
+        out_stream.flush()?;
+        line.clear();
+    }
+
+    expander.finish(&mut out_stream)?;
+
+    if let Some(epilogue) = macro_defs.epilogue() {
+        out_stream.write(epilogue)?;
+    }
+
+    out_stream.flush()
+}
+
 /**
  * Represents a Slang macro expansion task
- * If in_file and out_file strings are not provided stdin and stdout
+ * If in_files and out_file strings are not provided stdin and stdout
  * will be used instead
+ * Several in_files are expanded in order into one output, separated by join
+ * The defines are inline definitions written without `#define`, as in `foo : bar`,
+ * which are read before any macrofiles
+ * The macrofiles listed in macro_manifest are loaded before macro_files
+ * If combined is set the input begins with a section of macro definitions
+ * If line_directives is set it is the format of the directive written before each expansion
+ * The prologue and epilogue are written verbatim once before and after the whole output, not around each input
+ * The context holds the values of template context variables, given as NAME=VALUE
+ * The side_outputs map the names of side outputs written by templates to their files
+ * If recover is set macro definitions with syntax errors are skipped rather than fatal
+ * If placeholders is set templates may use variables no pattern binds, which are written as `<?name>`
+ * The on_conflict policy decides between definitions with conflicting patterns from different macrofiles,
+ * while conflicts within one macrofile are always errors
+ * The on_render_error policy decides whether a template failing to render aborts or leaves its input unexpanded,
+ * which can only happen for a variable bound by some alternatives of its pattern but not the one that matched
+ * The features enable the definitions guarded by them
+ * If recursive is set the output of each expansion is expanded again, up to that depth
+ * If strict_blocks is set a block pattern finding the wrong kind of block is an error
+ * If ignore_case is set literal tokens and back-references in patterns match ignoring case
+ * If timeout is set expanding each input fails once it has taken that long
+ * The out_file may only be one of the in_files if overwrite_input is set
+ * If stream is set the input is expanded as it is read, keeping only a window of it in memory,
+ * which rules out recursive, line_directives and timeout
+ * The output is buffered buffer_size bytes at a time, or unbuffered if it is zero
+ * If side_by_side is set each expansion is written as its original text followed by its expansion
+ * If range is set only the tokens lying entirely within that range of bytes of the single input are expanded,
+ * without the prologue and epilogue
+ * If check is set the macro definitions are only read, to check that they are valid and summarize them
+ * If show_config is set the tokenizer and macro settings are printed instead of expanding anything
+ * If dump_macros is set every macro read is printed instead of expanding anything
+ * If dump_dot is set the trie of macros read is printed as a Graphviz DOT graph instead of expanding anything
+ * If usage_report is set the number of times each macro pattern was expanded is written to it
+ * The tokenizer is read from tokenizer_config if it is set, instead of being the default one
+ * The characters in singletons and separators are added to those of that tokenizer
+ * The characters in quotes open string literals, which are read as a single token
+ * If escape is set it makes the character after it part of a token, and is dropped from templates
  */
 struct Task {
+    defines: Vec<String>,
     macro_files: Vec<String>,
-    in_file: Option<String>,
-    out_file: Option<String>
+    macro_manifest: Option<String>,
+    combined: bool,
+    line_directives: Option<String>,
+    prologue: Option<String>,
+    epilogue: Option<String>,
+    context: HashMap<String, String>,
+    side_outputs: HashMap<String, String>,
+    features: HashSet<String>,
+    recover: bool,
+    placeholders: bool,
+    on_conflict: ConflictPolicy,
+    on_render_error: RenderErrorPolicy,
+    recursive: Option<usize>,
+    timeout: Option<Duration>,
+    strict_blocks: bool,
+    ignore_case: bool,
+    usage_report: Option<String>,
+    tokenizer_config: Option<String>,
+    singletons: Option<String>,
+    separators: Option<String>,
+    quotes: Option<String>,
+    escape: Option<char>,
+    in_files: Vec<String>,
+    join: String,
+    out_file: Option<String>,
+    overwrite_input: bool,
+    stream: bool,
+    buffer_size: usize,
+    side_by_side: bool,
+    range: Option<(usize, usize)>,
+    show_config: bool,
+    dump_macros: bool,
+    dump_dot: bool,
+    check: bool
 }
 
-fn get_task() -> Result<Task> {
+fn get_task<I, T>(args: I) -> Result<Task>
+    where
+        I: IntoIterator<Item=T>,
+        T: Into<OsString> + Clone {
+
     let app = get_app();
-    let matches = app.get_matches();
+    let matches = app.get_matches_from(args);
+
+    let recursive = match matches.value_of("recursive") {
+        Some(depth) => match depth.parse() {
+            Ok(depth) if depth <= MAX_RECURSION_DEPTH => Some(depth),
+
+            Ok(_) => return Err(Error::new(ErrorKind::InvalidInput,
+                format!("Recursion depth `{}` is above the maximum of {}", depth, MAX_RECURSION_DEPTH))),
+
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid recursion depth `{}`", depth)))
+        },
+        None => None
+    };
+
+    let timeout = match matches.value_of("timeout") {
+        Some(millis) => Some(Duration::from_millis(millis.parse().map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, format!("Invalid timeout `{}`", millis))
+        })?)),
+        None => None
+    };
+
+    let buffer_size = matches.value_of("buffersize")
+        .unwrap_or_default()
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid buffer size"))?;
+
+    let range = match matches.value_of("range") {
+        Some(range) => {
+            let bounds = range.split_once("..")
+                .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+
+            match bounds {
+                Some((start, end)) if start <= end => Some((start, end)),
+                _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid range `{}`", range)))
+            }
+        },
+        None => None
+    };
+
+    let escape = match matches.value_of("escape") {
+        Some(escape) => {
+            let mut chars = escape.chars();
+
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid escape character `{}`", escape)))
+            }
+        },
+        None => None
+    };
+
+    let context = parse_assignments(matches.values_of("set").into_iter().flatten())?;
+    let side_outputs = parse_assignments(matches.values_of("sideoutput").into_iter().flatten())?;
 
     Ok(Task {
+        defines: matches
+            .values_of("define")
+            .map(|values| values.map(&str::to_string).collect())
+            .unwrap_or_default(),
+
         macro_files: matches
             .values_of("macrofiles")
-            .ok_or(Error::new(ErrorKind::InvalidInput, "Must include at least one macrofile"))?
-            .map(&str::to_string)
-            .collect(),
+            .map(|values| values.map(&str::to_string).collect())
+            .unwrap_or_default(),
+
+        macro_manifest: matches
+            .value_of("macromanifest")
+            .map(&str::to_string),
+
+        combined: matches.is_present("combined"),
+
+        line_directives: matches
+            .value_of("linedirectives")
+            .map(&str::to_string),
+
+        prologue: matches
+            .value_of("prologue")
+            .map(&str::to_string),
+
+        epilogue: matches
+            .value_of("epilogue")
+            .map(&str::to_string),
+
+        context,
+
+        side_outputs,
+
+        features: matches
+            .values_of("feature")
+            .map(|values| values.map(&str::to_string).collect())
+            .unwrap_or_default(),
+
+        recover: matches.is_present("recover"),
+
+        placeholders: matches.is_present("placeholders"),
+
+        on_conflict: match matches.value_of("onconflict") {
+            Some("first") => ConflictPolicy::First,
+            Some("last") => ConflictPolicy::Last,
+            _ => ConflictPolicy::Error
+        },
+
+        on_render_error: match matches.value_of("onrendererror") {
+            Some("warn") => RenderErrorPolicy::PassthroughWarn,
+            _ => RenderErrorPolicy::Abort
+        },
+
+        recursive,
+
+        timeout,
+
+        strict_blocks: matches.is_present("strictblocks"),
+
+        ignore_case: matches.is_present("ignorecase"),
+
+        usage_report: matches
+            .value_of("usagereport")
+            .map(&str::to_string),
+
+        tokenizer_config: matches
+            .value_of("tokenizerconfig")
+            .map(&str::to_string),
+
+        singletons: matches
+            .value_of("singletons")
+            .map(&str::to_string),
+
+        separators: matches
+            .value_of("separators")
+            .map(&str::to_string),
 
-        in_file: matches
-            .value_of("infile")
+        quotes: matches
+            .value_of("quotes")
             .map(&str::to_string),
 
+        escape,
+
+        in_files: matches
+            .values_of("infile")
+            .map(|values| values.map(&str::to_string).collect())
+            .unwrap_or_default(),
+
+        join: matches
+            .value_of("join")
+            .unwrap_or_default()
+            .to_string(),
+
         out_file: matches
             .value_of("outfile")
-            .map(&str::to_string)
+            .map(&str::to_string),
+
+        overwrite_input: matches.is_present("overwriteinput"),
+
+        stream: matches.is_present("stream"),
+
+        buffer_size,
+
+        side_by_side: matches.is_present("sidebyside"),
+
+        range,
+
+        show_config: matches.is_present("showconfig"),
+
+        dump_macros: matches.is_present("dumpmacros"),
+
+        dump_dot: matches.is_present("dumpdot"),
+
+        check: matches.is_present("check")
     })
 }
 
+/// Splits each NAME=VALUE setting at its first `=`
+fn parse_assignments<'a, T>(settings: T) -> Result<HashMap<String, String>>
+    where
+        T: IntoIterator<Item=&'a str> {
+
+    let mut assignments = HashMap::new();
+
+    for setting in settings {
+        match setting.find('=') {
+            Some(split) => {
+                assignments.insert(setting[ .. split].to_string(), setting[split + 1 .. ].to_string());
+            },
+
+            None => {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Expected NAME=VALUE, found `{}`", setting)));
+            }
+        }
+    }
+
+    Ok(assignments)
+}
+
 fn get_app() -> App<'static, 'static> {
     App::new("Slang")
         .version("0.1.0")
         .author("Kyle Brown <kylebrw@gmail.com>")
         .about("A macro expansion program for simple language abstractions")
         .arg(Arg::with_name("macrofiles")
-                .help("Macro definition files")
+                .help("Macro definition files, directories of .slang files, or file names with the wildcards * and ?")
                 .multiple(true)
                 .takes_value(true)
                 .min_values(1)
         )
+        .arg(Arg::with_name("define")
+                .help("An inline macro definition such as `foo : bar`, which may be repeated")
+                .short("D")
+                .long("define")
+                .value_name("DEFINITION")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(Arg::with_name("macromanifest")
+                .help("A file listing macro definition files, one per line")
+                .long("macro-manifest")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("combined")
+                .help("Read macro definitions from the input, before a line containing only ---")
+                .long("combined")
+        )
+        .arg(Arg::with_name("linedirectives")
+                .help("Write a directive before each expansion, replacing {line} and {file} in FORMAT")
+                .long("line-directives")
+                .value_name("FORMAT")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("prologue")
+                .help("Text written verbatim once before the whole expanded output")
+                .long("prologue")
+                .value_name("TEXT")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("epilogue")
+                .help("Text written verbatim once after the whole expanded output")
+                .long("epilogue")
+                .value_name("TEXT")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("set")
+                .help("Set a context variable, used in templates as $$NAME")
+                .long("set")
+                .value_name("NAME=VALUE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(Arg::with_name("sideoutput")
+                .help("Write the side output NAME of templates, from $to NAME { .. }, to PATH")
+                .long("side-output")
+                .value_name("NAME=PATH")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(Arg::with_name("feature")
+                .help("Enable the definitions guarded by #[if feature=NAME]")
+                .long("feature")
+                .value_name("NAME")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(Arg::with_name("recover")
+                .help("Skip macro definitions with syntax errors, with a warning, and read the rest")
+                .long("recover")
+        )
+        .arg(Arg::with_name("placeholders")
+                .help("Allow templates to use variables no pattern binds, writing them as <?NAME> for a later pass to fill")
+                .long("placeholders")
+        )
+        .arg(Arg::with_name("onconflict")
+                .help("Whether a definition conflicting with one from an earlier source is an error, or the first or last of them is kept")
+                .long("on-conflict")
+                .value_name("POLICY")
+                .possible_values(&["error", "first", "last"])
+                .default_value("error")
+        )
+        .arg(Arg::with_name("onrendererror")
+                .help("Whether a template using a variable its matching alternative does not bind aborts, or leaves the input unexpanded with a warning")
+                .long("on-render-error")
+                .value_name("POLICY")
+                .possible_values(&["abort", "warn"])
+                .default_value("abort")
+        )
+        .arg(Arg::with_name("recursive")
+                .help("Expand the output of each expansion again, nesting at most DEPTH times, which can be up to 128")
+                .long("recursive")
+                .value_name("DEPTH")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("timeout")
+                .help("Abort expanding an input that takes longer than MS milliseconds")
+                .long("timeout")
+                .value_name("MS")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("strictblocks")
+                .help("Report an error where a block pattern finds a different kind of block")
+                .long("strict-blocks")
+        )
+        .arg(Arg::with_name("ignorecase")
+                .help("Match the literal tokens and back-references of patterns ignoring case")
+                .long("ignore-case")
+        )
+        .arg(Arg::with_name("usagereport")
+                .help("Write the number of times each macro pattern was expanded to FILE")
+                .long("usage-report")
+                .value_name("FILE")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("tokenizerconfig")
+                .help("Read the tokenizer's characters from FILE instead of using the default ones")
+                .long("tokenizer-config")
+                .value_name("FILE")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("singletons")
+                .help("Extra characters that are always a token by themselves")
+                .long("singletons")
+                .value_name("CHARS")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("separators")
+                .help("Extra characters that separate tokens")
+                .long("separators")
+                .value_name("CHARS")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("quotes")
+                .help("Characters that open and close string literals, which are read as a single token")
+                .long("quotes")
+                .value_name("CHARS")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("escape")
+                .help("A character making the one after it part of a token, which templates leave out, as in \\{")
+                .long("escape")
+                .value_name("CHAR")
+                .takes_value(true)
+        )
         .arg(Arg::with_name("infile")
-                .help("The input file to macro expand")
+                .help("An input file to macro expand, which may be repeated to concatenate several")
                 .short("i")
                 .long("input")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(Arg::with_name("join")
+                .help("The separator written between the outputs of multiple input files")
+                .long("join")
+                .value_name("SEP")
+                .takes_value(true)
         )
         .arg(Arg::with_name("outfile")
                 .help("The file to write the macro expanded input to")
@@ -135,4 +794,201 @@ fn get_app() -> App<'static, 'static> {
                 .long("output")
                 .takes_value(true)
         )
-}
\ No newline at end of file
+        .arg(Arg::with_name("stream")
+                .help("Expand the input as it is read, for macros without block or sequence variables")
+                .long("stream")
+                .conflicts_with_all(&["recursive", "linedirectives", "timeout"])
+        )
+        .arg(Arg::with_name("sidebyside")
+                .help("Write each expansion as its original text followed by its expansion, for review")
+                .long("side-by-side")
+        )
+        .arg(Arg::with_name("range")
+                .help("Only expand the tokens lying entirely within the bytes START to END of the input, passing the rest through")
+                .long("range")
+                .value_name("START..END")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("check")
+                .help("Only read the macro definitions, reporting statistics about the patterns they define or why they are invalid")
+                .long("check")
+        )
+        .arg(Arg::with_name("showconfig")
+                .help("Print the tokenizer and macro settings in effect after reading the macrofiles, then exit")
+                .long("show-config")
+        )
+        .arg(Arg::with_name("dumpmacros")
+                .help("Print the pattern and template of every macro read, then exit")
+                .long("dump-macros")
+        )
+        .arg(Arg::with_name("dumpdot")
+                .help("Print the trie of every macro read as a Graphviz DOT graph, then exit")
+                .long("dump-dot")
+        )
+        .arg(Arg::with_name("buffersize")
+                .help("The number of bytes of output to buffer between writes, or 0 to write without buffering")
+                .long("buffer-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("8192")
+        )
+        .arg(Arg::with_name("overwriteinput")
+                .help("Allow the output file to replace an input file once expansion succeeds")
+                .long("overwrite-input")
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use io_helpers::temp_dir;
+    use tokenizer::TokenizerBuilder;
+
+    #[test]
+    fn checks_macrofiles() {
+        let dir = temp_dir("check");
+        let valid = dir.join("valid.slang");
+        let broken = dir.join("broken.slang");
+
+        fs::write(&valid, "#define foo : bar").unwrap();
+        fs::write(&broken, "#define foo : bar\n#define baz qux\n").unwrap();
+
+        let check = |path: &Path| run_command(get_task(vec!["slang", "--check", path.to_str().unwrap()]).unwrap());
+
+        assert!(check(&valid).is_ok());
+        assert_eq!(check(&broken).unwrap_err().to_string(), "Macro definition is missing `:`");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn streams_with_prologue_epilogue_and_usage_report() {
+        let dir = temp_dir("stream");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        fs::write(path("macros.slang"), "#define foo : bar").unwrap();
+        fs::write(path("in.txt"), "foo x\nfoo\n").unwrap();
+
+        let task = get_task(vec![
+            "slang", "--stream", "--prologue", "<", "--epilogue", ">",
+            "--usage-report", &path("usage.txt"),
+            "-i", &path("in.txt"), "-o", &path("out.txt"), &path("macros.slang")
+        ]).unwrap();
+
+        run_command(task).unwrap();
+
+        assert_eq!(fs::read_to_string(path("out.txt")).unwrap(), "<bar x\nbar\n>");
+        assert_eq!(fs::read_to_string(path("usage.txt")).unwrap(), "2\tfoo\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_inline_definitions() {
+        let dir = temp_dir("define");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        fs::write(path("in.txt"), "foo baz ( x )\n").unwrap();
+
+        let task = get_task(vec![
+            "slang", "-D", "foo : bar", "--define", "baz ( $x ) : [$x]",
+            "-i", &path("in.txt"), "-o", &path("out.txt")
+        ]).unwrap();
+
+        run_command(task).unwrap();
+
+        assert_eq!(fs::read_to_string(path("out.txt")).unwrap(), "bar [x]\n");
+
+        let task = get_task(vec!["slang", "-D", "foo bar", "-i", &path("in.txt"), "-o", &path("out.txt")]).unwrap();
+        let error = run_command(task).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().starts_with("Invalid --define `foo bar`: "), "{}", error);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_placeholders_for_unbound_vars() {
+        let dir = temp_dir("placeholders");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        fs::write(path("in.txt"), "call f\n").unwrap();
+
+        let (in_path, out_path) = (path("in.txt"), path("out.txt"));
+        let args = vec!["slang", "-D", "call $f : $f($args)", "-i", &in_path, "-o", &out_path];
+
+        let error = run_command(get_task(args.clone()).unwrap()).unwrap_err();
+        assert!(error.to_string().ends_with("Template uses unbound variable `$args`"), "{}", error);
+
+        run_command(get_task([args, vec!["--placeholders"]].concat()).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(path("out.txt")).unwrap(), "f(<?args>)\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_render_error_policy() {
+        let policy = |args: Vec<&str>| get_task(args).unwrap().on_render_error;
+
+        assert_eq!(policy(vec!["slang", "macros.slang"]), RenderErrorPolicy::Abort);
+        assert_eq!(policy(vec!["slang", "--on-render-error", "warn", "macros.slang"]), RenderErrorPolicy::PassthroughWarn);
+    }
+
+    #[test]
+    fn expands_only_the_given_range() {
+        let dir = temp_dir("range");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        fs::write(path("in.txt"), "foo foo foo\n").unwrap();
+
+        let task = get_task(vec!["slang", "-D", "foo : bar", "--range", "4..8", "-i", &path("in.txt"), "-o", &path("out.txt")]).unwrap();
+        run_command(task).unwrap();
+
+        assert_eq!(fs::read_to_string(path("out.txt")).unwrap(), "foo bar foo\n");
+
+        for range in ["8..4", "4", "a..b"].iter() {
+            let error = get_task(vec!["slang", "--range", range, "macros.slang"]).err().unwrap();
+            assert_eq!(error.to_string(), format!("Invalid range `{}`", range));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_recursion_beyond_the_maximum() {
+        let depth = |depth: usize| get_task(vec!["slang", "--recursive", &depth.to_string(), "macros.slang"]).map(|task| task.recursive);
+
+        assert_eq!(depth(MAX_RECURSION_DEPTH).unwrap(), Some(MAX_RECURSION_DEPTH));
+        assert_eq!(depth(MAX_RECURSION_DEPTH + 1).unwrap_err().to_string(), "Recursion depth `129` is above the maximum of 128");
+    }
+
+    #[test]
+    fn stream_rejects_whole_input_options() {
+        for option in &["--recursive=2", "--line-directives=#line {line}", "--timeout=100"] {
+            let matches = get_app().get_matches_from_safe(vec!["slang", "--stream", option, "macros.slang"]);
+
+            assert!(matches.is_err(), "{} was accepted", option);
+        }
+    }
+
+    #[test]
+    fn describes_every_tokenizer_setting() {
+        let tokenizer = TokenizerBuilder::new()
+            .singletons("()".chars())
+            .multi_singletons(vec!["->".to_string(), "::".to_string()])
+            .separator(' ')
+            .quote_chars("\"".chars())
+            .escape_char(Some('\\'))
+            .line_comment(Some("//".to_string()))
+            .drop_comments(true)
+            .build();
+
+        assert_eq!(describe_tokenizer(&tokenizer), "singletons: '(' ')'\nmulti_singletons: \"->\" \"::\"\nseparators: ' '\n\
+            quotes: '\"'\nescape: '\\\\'\nline_comment: \"//\"\ndrop_comments: true\n");
+
+        assert_eq!(describe_tokenizer(&TokenizerBuilder::new().build()), "singletons: \nmulti_singletons: \nseparators: \n\
+            quotes: \nescape: none\nline_comment: none\ndrop_comments: false\n");
+    }
+}
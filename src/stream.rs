@@ -0,0 +1,163 @@
+use std::io::{ Result, Error, ErrorKind };
+
+use crate::io_helpers::SimpleOutput;
+use crate::macro_def::Macros;
+use crate::tokenizer::{ Token, Tokenizer };
+
+/// Expands input that arrives in chunks, such as the lines of a log,
+/// keeping only a window of tokens in memory rather than the whole input.
+///
+/// The window holds one more complete token than the longest pattern,
+/// so a match at its start is always decided by tokens already read.
+/// Since the last token read may yet be extended by the next chunk,
+/// it is never part of the window until more input (or the end) arrives.
+/// Only macro sets whose patterns have a bounded length can be streamed,
+/// and line directives, recursion and timeouts do not apply.
+pub struct StreamExpander<'m> {
+    macros: &'m Macros,
+    tokenizer: &'m Tokenizer,
+    window: usize,
+
+    /// The input read but not yet expanded, starting at a token
+    pending: String,
+
+    /// The most bytes pending at once, for checking that buffering stays bounded
+    peak_pending: usize
+}
+
+impl<'m> StreamExpander<'m> {
+    pub fn new(macros: &'m Macros, tokenizer: &'m Tokenizer) -> Result<Self> {
+        let max_pattern_len = macros.max_pattern_len()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Streaming expansion needs patterns without block or sequence variables"))?;
+
+        Ok(StreamExpander {
+            macros,
+            tokenizer,
+            window: max_pattern_len + 1,
+            pending: String::new(),
+            peak_pending: 0
+        })
+    }
+
+    /// Adds a chunk of input, writing out everything that it lets be expanded
+    pub fn push(&mut self, chunk: &str, out_stream: &mut SimpleOutput) -> Result<()> {
+        self.pending.push_str(chunk);
+        self.peak_pending = self.peak_pending.max(self.pending.len());
+
+        let tokens = self.tokenizer.tokenize(&self.pending);
+
+        //The final token may continue into the next chunk
+        let complete = tokens.len().saturating_sub(1);
+
+        let mut position = 0;
+        let mut expanded_bytes = 0;
+
+        while complete - position >= self.window {
+            let window = &tokens[position .. position + self.window];
+            let consumed = self.expand_start(window, out_stream)?;
+
+            expanded_bytes += window[ .. consumed].iter()
+                .map(|token| token.value.len() + token.suffix.len())
+                .sum::<usize>();
+
+            position += consumed;
+        }
+
+        self.pending.drain( .. expanded_bytes);
+
+        Ok(())
+    }
+
+    /// Expands whatever input is still pending, now that no more will arrive
+    pub fn finish(self, out_stream: &mut SimpleOutput) -> Result<()> {
+        let tokens = self.tokenizer.tokenize(&self.pending);
+        let mut position = 0;
+
+        while position < tokens.len() {
+            let window = &tokens[position .. tokens.len().min(position + self.window)];
+
+            position += self.expand_start(window, out_stream)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the expansion of the macro matching the start of window, or its first token
+    /// if none does, returning the number of tokens consumed
+    fn expand_start(&self, window: &[Token], out_stream: &mut SimpleOutput) -> Result<usize> {
+        match self.macros.expand_next(window)? {
+            Some((rendered, consumed)) => {
+                out_stream.write(&rendered)?;
+                out_stream.write(window[consumed - 1].suffix)?;
+
+                Ok(consumed)
+            },
+
+            None => {
+                out_stream.write(window[0].value)?;
+                out_stream.write(window[0].suffix)?;
+
+                Ok(1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::{ simplify_output, SharedBuffer };
+
+    #[test]
+    fn buffers_a_bounded_window() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize("#define warn $x : WARNING($x) #define ok : fine")).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        let mut expander = StreamExpander::new(&macros, &tokenizer).unwrap();
+        let mut expected = String::new();
+
+        for line in 0 .. 10_000 {
+            expander.push(&format!("{} warn disk ok\n", line), &mut out_stream).unwrap();
+            expected.push_str(&format!("{} WARNING(disk) fine\n", line));
+        }
+
+        let peak_pending = expander.peak_pending;
+        expander.finish(&mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), expected);
+        assert!(peak_pending < 64, "Buffered {} bytes", peak_pending);
+    }
+
+    #[test]
+    fn matches_across_chunks() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize("#define begin $x end : [$x]")).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut out_stream = simplify_output(Box::new(buffer.clone()));
+
+        let mut expander = StreamExpander::new(&macros, &tokenizer).unwrap();
+
+        for line in &["a begin\n", "b\n", "end c\n", "begin d end\n"] {
+            expander.push(line, &mut out_stream).unwrap();
+        }
+
+        expander.finish(&mut out_stream).unwrap();
+
+        assert_eq!(buffer.contents(), "a [b] c\n[d]\n");
+    }
+
+    #[test]
+    fn rejects_unbounded_patterns() {
+        let tokenizer = Tokenizer::default();
+        let mut macros = Macros::new();
+        macros.read_macros(&tokenizer.tokenize("#define call ( $args ) : $args")).unwrap();
+
+        assert!(StreamExpander::new(&macros, &tokenizer).is_err());
+    }
+}
@@ -1,87 +1,352 @@
 use std::collections::{ HashSet };
+use std::io::{ Result, Error, ErrorKind };
+use std::fs;
+use std::path::Path;
 
 /**
  * The singletons set indicates what characters should always be a token by themselves
  * The separators set indicates what characters indicate the boundary between tokens
+ * The multi_singletons are strings that should always be a token by themselves,
+ * kept longest first so that the longest one matching wins
+ * The quote_chars open string literals, which run up to the next of the same character
+ * and are a single token, quotes included
+ * The escape_char, if any, makes the character after it part of the current value,
+ * even if it is a singleton, separator or quote; the escape_char is kept in the value.
+ * Line breaks cannot be escaped, so that a `\` at the end of a template line still continues it.
+ * There is no escape_char unless one is set, so that input tokenizes the same as without escapes.
+ * The line_comment prefix, if any, starts a comment running up to the next newline,
+ * which is part of the suffix of the token before it unless drop_comments is set
  */
+#[derive(Clone)]
 pub struct Tokenizer {
     singletons: HashSet<char>,
-    separators: HashSet<char>
+    separators: HashSet<char>,
+    multi_singletons: Vec<String>,
+    quote_chars: HashSet<char>,
+    line_comment: Option<String>,
+    drop_comments: bool,
+    escape_char: Option<char>
 }
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+/// A token along with the separators following it.
+/// The line and column where its value starts count from 1,
+/// with each character including tabs being one column.
+/// Tokens not read from input, such as those made with new, are at line and column 0.
+#[derive(Debug, Clone)]
 pub struct Token<'a> {
     pub value: &'a str,
-    pub suffix: &'a str
+    pub suffix: &'a str,
+    pub line: u32,
+    pub column: u32
 }
 
 impl<'a> Token<'a> {
-    #[cfg(test)]
     pub fn new(value: &'a str, suffix: &'a str) -> Self {
-        Token { value, suffix }
+        Token { value, suffix, line: 0, column: 0 }
     }
 }
 
+/// Tokens are equal if their text is, wherever they were read from
+impl<'a> PartialEq for Token<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value && self.suffix == other.suffix
+    }
+}
+
+impl<'a> Eq for Token<'a> {}
+
 impl Default for Tokenizer {
     fn default() -> Self {
-        let singletons: HashSet<char> = ['[', ']', '{', '}', '(', ')', ',', ':', '#'].iter().cloned().collect();
-        let separators: HashSet<char> = [' ', '\n', '\r', '\t'].iter().cloned().collect();
-
-        Tokenizer::new(singletons, separators)
+        TokenizerBuilder::new()
+            .singletons(['[', ']', '{', '}', '(', ')', ',', ':', '#'])
+            .separators([' ', '\n', '\r', '\t'])
+            .build()
     }
 }
 
 impl Tokenizer {
-    pub fn new(singletons: HashSet<char>, separators: HashSet<char>) -> Self {
-        Tokenizer { singletons, separators }
+    pub fn new(singletons: HashSet<char>, separators: HashSet<char>, multi_singletons: HashSet<String>) -> Self {
+        let mut multi_singletons: Vec<String> = multi_singletons.into_iter()
+            .filter(|singleton| !singleton.is_empty())
+            .collect();
+
+        multi_singletons.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+
+        Tokenizer {
+            singletons,
+            separators,
+            multi_singletons,
+            quote_chars: HashSet::new(),
+            line_comment: None,
+            drop_comments: false,
+            escape_char: None
+        }
+    }
+
+    /// Reads a tokenizer from a config file, see parse_config for its format
+    pub fn from_config_file(path: &Path) -> Result<Tokenizer> {
+        Tokenizer::parse_config(&fs::read_to_string(path)?)
     }
 
+    /// Reads a tokenizer from lines of the form `key = items`,
+    /// whose items are separated by whitespace, as in `singletons = ( ) ;`.
+    /// The keys are singletons, separators, multi_singletons, quotes, escape, line_comment and drop_comments,
+    /// which is `true` or `false`.
+    /// Whitespace and backslashes in items are written as `\s`, `\t`, `\n`, `\r` and `\\`.
+    /// Blank lines and lines starting with `#` are ignored.
+    /// Only what the config lists is used, none of the default tokenizer's characters.
+    pub fn parse_config(config: &str) -> Result<Tokenizer> {
+        let mut builder = TokenizerBuilder::new();
+
+        for (line_index, line) in config.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(CONFIG_COMMENT) {
+                continue;
+            }
+
+            let config_error = |message: String| Error::new(ErrorKind::InvalidData, format!(
+                "{} on line {} of the tokenizer config", message, line_index + 1));
+
+            let (key, items) = match line.find('=') {
+                Some(equals) => (line[ .. equals].trim(), &line[equals + 1 .. ]),
+                None => return Err(config_error(format!("Expected `key = items` but found `{}`", line)))
+            };
+
+            let items = items.split_whitespace()
+                .map(unescape_config_item)
+                .collect::<std::result::Result<Vec<String>, String>>()
+                .map_err(config_error)?;
+
+            let chars = || items.iter()
+                .map(|item| {
+                    let mut chars = item.chars();
+
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Ok(c),
+                        _ => Err(config_error(format!("`{}` is not a single character", item)))
+                    }
+                })
+                .collect::<Result<Vec<char>>>();
+
+            builder = match key {
+                "singletons" => builder.singletons(chars()?),
+                "separators" => builder.separators(chars()?),
+                "quotes" => builder.quote_chars(chars()?),
+                "multi_singletons" => builder.multi_singletons(items),
+
+                "escape" => match chars()?.as_slice() {
+                    [c] => builder.escape_char(Some(*c)),
+                    [] => builder.escape_char(None),
+                    _ => return Err(config_error("Expected at most one escape character".to_string()))
+                },
+
+                "line_comment" => match items.as_slice() {
+                    [prefix] => builder.line_comment(Some(prefix.clone())),
+                    _ => return Err(config_error("Expected a single line comment prefix".to_string()))
+                },
+
+                "drop_comments" => match items.as_slice() {
+                    [value] if value == "true" => builder.drop_comments(true),
+                    [value] if value == "false" => builder.drop_comments(false),
+                    _ => return Err(config_error("Expected `true` or `false`".to_string()))
+                },
+
+                _ => return Err(config_error(format!("Unknown tokenizer setting `{}`", key)))
+            };
+        }
+
+        Ok(builder.build())
+    }
+
+    pub fn singletons(&self) -> &HashSet<char> {
+        &self.singletons
+    }
+
+    pub fn separators(&self) -> &HashSet<char> {
+        &self.separators
+    }
+
+    pub fn multi_singletons(&self) -> &[String] {
+        &self.multi_singletons
+    }
+
+    pub fn quote_chars(&self) -> &HashSet<char> {
+        &self.quote_chars
+    }
+
+    /// Adds characters, which need not be ASCII, that should always be a token by themselves
+    pub fn extend_singletons<T>(&mut self, singletons: T)
+        where
+            T: IntoIterator<Item=char> {
+
+        self.singletons.extend(singletons);
+    }
+
+    /// Adds characters, which need not be ASCII, that indicate the boundary between tokens
+    pub fn extend_separators<T>(&mut self, separators: T)
+        where
+            T: IntoIterator<Item=char> {
+
+        self.separators.extend(separators);
+    }
+
+    /// Adds characters that open and close string literals, such as `"` and `'`
+    pub fn extend_quote_chars<T>(&mut self, quote_chars: T)
+        where
+            T: IntoIterator<Item=char> {
+
+        self.quote_chars.extend(quote_chars);
+    }
+
+    pub fn line_comment(&self) -> Option<&str> {
+        self.line_comment.as_deref()
+    }
+
+    /// Sets the prefix starting comments that run to the end of the line, such as `//`,
+    /// or stops recognizing comments if it is None or empty
+    pub fn set_line_comment(&mut self, prefix: Option<&str>) {
+        self.line_comment = prefix
+            .filter(|prefix| !prefix.is_empty())
+            .map(str::to_string);
+    }
+
+    pub fn drop_comments(&self) -> bool {
+        self.drop_comments
+    }
+
+    /// Sets whether comments are left out of tokens entirely,
+    /// in which case render_tokens no longer reproduces the input
+    pub fn set_drop_comments(&mut self, drop_comments: bool) {
+        self.drop_comments = drop_comments;
+    }
+
+    pub fn escape_char(&self) -> Option<char> {
+        self.escape_char
+    }
+
+    /// Sets the character escaping the one after it, or stops escaping characters if it is None
+    pub fn set_escape_char(&mut self, escape_char: Option<char>) {
+        self.escape_char = escape_char;
+    }
+
+    /// Splits input into tokens, so that render_tokens reproduces it exactly
+    /// unless comments are dropped.
+    /// Separators at the start of input are the suffix of a first token with an empty value.
     pub fn tokenize<'a>(&self, input: &'a str) -> Vec<Token<'a>> {
-        let mut output = Vec::new();
-        let mut start = input;
+        self.tokens(input).collect()
+    }
+
+    /// Reads the same tokens as tokenize, but only as they are needed
+    pub fn tokens<'t, 'a>(&'t self, input: &'a str) -> Tokens<'t, 'a> {
+        Tokens {
+            tokenizer: self,
+            remaining: input,
+            line: 1,
+            column: 1
+        }
+    }
 
-        loop {
-            let ParseResult { contents: value, remaining: post_val } = self.read_value(start);
-            let ParseResult { contents: suffix, remaining: post_suf } = self.read_suffix(post_val);
+    /// The longest multi-character singleton that input starts with, if any
+    #[inline]
+    fn multi_singleton_at(&self, input: &str) -> Option<&str> {
+        self.multi_singletons.iter()
+            .find(|singleton| input.starts_with(singleton.as_str()))
+            .map(String::as_str)
+    }
 
-            if value.is_empty() && suffix.is_empty() {
-                break;
+    /// The length of the escape and the character it escapes, if input starts with them
+    #[inline]
+    fn escape_at(&self, input: &str) -> Option<usize> {
+        let mut chars = input.chars();
+
+        match (self.escape_char, chars.next(), chars.next()) {
+            (Some(escape_char), Some(c), Some(escaped)) if c == escape_char && escaped != '\n' && escaped != '\r' => {
+                Some(c.len_utf8() + escaped.len_utf8())
+            },
+
+            _ => None
+        }
+    }
+
+    /// The end of the quoted literal opened by the quote at the start of input,
+    /// skipping escaped quotes. An unterminated literal runs to the end of the input.
+    #[inline]
+    fn quoted_end(&self, input: &str, quote: char) -> usize {
+        let mut index = quote.len_utf8();
+
+        while let Some(c) = input[index .. ].chars().next() {
+            if let Some(escape_len) = self.escape_at(&input[index .. ]) {
+                index += escape_len;
+            } else if c == quote {
+                return index + c.len_utf8();
             } else {
-                output.push(Token { value, suffix });
+                index += c.len_utf8();
             }
-
-            start = post_suf;
         }
 
-        output
+        input.len()
     }
 
+    /// Whether input starts with a comment
     #[inline]
-    fn read_value<'a>(&self, input: &'a str) -> ParseResult<'a> {
-        let mut iter = input.char_indices();
+    fn comment_at(&self, input: &str) -> bool {
+        self.line_comment.as_ref().is_some_and(|prefix| input.starts_with(prefix.as_str()))
+    }
 
+    #[inline]
+    fn read_value<'a>(&self, input: &'a str) -> ParseResult<'a> {
         let mut short_cut = false;
         let mut value_end = 0;
 
-        if let Some((_, c)) = iter.next() {
-            if self.separators.contains(&c) {
+        //Where to look for the end of an ordinary value from
+        let mut scan_from = 0;
+
+        if self.escape_at(input).is_some() {
+            //An escaped character starts an ordinary value
+        } else if self.comment_at(input) {
+            //Only input starting with a comment has one here, which the suffix then holds
+            short_cut = true;
+        } else if let Some(singleton) = self.multi_singleton_at(input) {
+            short_cut = true;
+            value_end = singleton.len();
+        } else if let Some(c) = input.chars().next() {
+            if self.quote_chars.contains(&c) {
+                short_cut = true;
+                value_end = self.quoted_end(input, c);
+            } else if self.separators.contains(&c) {
                 short_cut = true;
             }
 
             if self.singletons.contains(&c) {
                 short_cut = true;
-                value_end = 1;
+                value_end = c.len_utf8();
             }
+
+            scan_from = c.len_utf8();
         }
 
         if !short_cut {
-            while let Some((index, c)) = iter.next() {
+            let mut index = scan_from;
+            value_end = input.len();
+
+            while let Some(c) = input[index .. ].chars().next() {
+                if let Some(escape_len) = self.escape_at(&input[index .. ]) {
+                    index += escape_len;
+                    continue;
+                }
+
                 if self.separators.contains(&c)
-                    || self.singletons.contains(&c) {
+                    || self.singletons.contains(&c)
+                    || self.quote_chars.contains(&c)
+                    || self.comment_at(&input[index .. ])
+                    || self.multi_singleton_at(&input[index .. ]).is_some() {
 
                     value_end = index;
                     break;
                 }
+
+                index += c.len_utf8();
             }
         }
 
@@ -93,24 +358,221 @@ impl Tokenizer {
 
     #[inline]
     fn read_suffix<'a>(&self, input: &'a str) -> ParseResult<'a> {
-        let mut iter = input.char_indices();
-
-        while let Some((index, c)) = iter.next() {
-            if !self.separators.contains(&c) {
-                return ParseResult {
-                    contents: &input[ .. index],
-                    remaining: &input[index .. ]
-                };
+        let mut suffix_start = 0;
+        let mut index = 0;
+
+        while let Some(c) = input[index .. ].chars().next() {
+            if self.comment_at(&input[index .. ]) {
+                let comment_end = input[index .. ].find('\n').map_or(input.len(), |newline| index + newline);
+
+                if self.drop_comments {
+                    suffix_start = comment_end;
+                }
+
+                index = comment_end;
+            } else if self.separators.contains(&c) {
+                index += c.len_utf8();
+            } else {
+                break;
             }
         }
 
         ParseResult {
-            contents: input,
-            remaining: &input[0 .. 0]
+            contents: &input[suffix_start .. index],
+            remaining: &input[index .. ]
+        }
+    }
+}
+
+/// Starts a line of a tokenizer config that is ignored
+const CONFIG_COMMENT: &str = "#";
+
+/// Replaces the escapes for whitespace and backslashes in an item of a tokenizer config
+fn unescape_config_item(item: &str) -> std::result::Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = item.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
         }
+
+        unescaped.push(match chars.next() {
+            Some('s') => ' ',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('\\') => '\\',
+            Some(other) => return Err(format!("Unknown escape `\\{}`", other)),
+            None => return Err(format!("`{}` ends in an unfinished escape", item))
+        });
+    }
+
+    Ok(unescaped)
+}
+
+/// Configures a Tokenizer a setting at a time,
+/// starting from nothing with new or from the default Tokenizer's settings with default
+pub struct TokenizerBuilder {
+    singletons: HashSet<char>,
+    separators: HashSet<char>,
+    multi_singletons: HashSet<String>,
+    quote_chars: HashSet<char>,
+    line_comment: Option<String>,
+    drop_comments: bool,
+    escape_char: Option<char>
+}
+
+impl TokenizerBuilder {
+    pub fn new() -> Self {
+        TokenizerBuilder {
+            singletons: HashSet::new(),
+            separators: HashSet::new(),
+            multi_singletons: HashSet::new(),
+            quote_chars: HashSet::new(),
+            line_comment: None,
+            drop_comments: false,
+            escape_char: None
+        }
+    }
+
+    pub fn singleton(mut self, singleton: char) -> Self {
+        self.singletons.insert(singleton);
+        self
+    }
+
+    pub fn singletons<T>(self, singletons: T) -> Self
+        where
+            T: IntoIterator<Item=char> {
+
+        singletons.into_iter().fold(self, TokenizerBuilder::singleton)
+    }
+
+    pub fn separator(mut self, separator: char) -> Self {
+        self.separators.insert(separator);
+        self
+    }
+
+    pub fn separators<T>(self, separators: T) -> Self
+        where
+            T: IntoIterator<Item=char> {
+
+        separators.into_iter().fold(self, TokenizerBuilder::separator)
+    }
+
+    pub fn multi_singletons<T>(mut self, multi_singletons: T) -> Self
+        where
+            T: IntoIterator<Item=String> {
+
+        self.multi_singletons.extend(multi_singletons);
+        self
+    }
+
+    pub fn quote_chars<T>(mut self, quote_chars: T) -> Self
+        where
+            T: IntoIterator<Item=char> {
+
+        self.quote_chars.extend(quote_chars);
+        self
+    }
+
+    pub fn line_comment(mut self, prefix: Option<String>) -> Self {
+        self.line_comment = prefix;
+        self
+    }
+
+    pub fn drop_comments(mut self, drop_comments: bool) -> Self {
+        self.drop_comments = drop_comments;
+        self
+    }
+
+    pub fn escape_char(mut self, escape_char: Option<char>) -> Self {
+        self.escape_char = escape_char;
+        self
+    }
+
+    pub fn build(self) -> Tokenizer {
+        let mut tokenizer = Tokenizer::new(self.singletons, self.separators, self.multi_singletons);
+        tokenizer.extend_quote_chars(self.quote_chars);
+        tokenizer.set_line_comment(self.line_comment.as_deref());
+        tokenizer.set_drop_comments(self.drop_comments);
+        tokenizer.set_escape_char(self.escape_char);
+
+        tokenizer
+    }
+}
+
+impl Default for TokenizerBuilder {
+    fn default() -> Self {
+        let tokenizer = Tokenizer::default();
+
+        TokenizerBuilder {
+            singletons: tokenizer.singletons,
+            separators: tokenizer.separators,
+            multi_singletons: tokenizer.multi_singletons.into_iter().collect(),
+            quote_chars: tokenizer.quote_chars,
+            line_comment: tokenizer.line_comment,
+            drop_comments: tokenizer.drop_comments,
+            escape_char: tokenizer.escape_char
+        }
+    }
+}
+
+/// An iterator over the tokens of some input, made by Tokenizer::tokens
+pub struct Tokens<'t, 'a> {
+    tokenizer: &'t Tokenizer,
+
+    /// The input not yet read
+    remaining: &'a str,
+
+    line: u32,
+    column: u32
+}
+
+impl<'t, 'a> Iterator for Tokens<'t, 'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let start = self.remaining;
+
+        let ParseResult { contents: value, remaining: post_val } = self.tokenizer.read_value(start);
+        let ParseResult { contents: suffix, remaining: post_suf } = self.tokenizer.read_suffix(post_val);
+
+        if value.is_empty() && suffix.is_empty() {
+            return None;
+        }
+
+        let token = Token { value, suffix, line: self.line, column: self.column };
+
+        //Dropped comments still take up lines
+        for c in start[ .. start.len() - post_suf.len()].chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+
+        self.remaining = post_suf;
+
+        Some(token)
     }
 }
 
+/// Reconstructs the text tokens were read from, each value followed by its suffix
+pub fn render_tokens(tokens: &[Token]) -> String {
+    let mut rendered = String::new();
+
+    for token in tokens {
+        rendered.push_str(token.value);
+        rendered.push_str(token.suffix);
+    }
+
+    rendered
+}
+
 struct ParseResult<'a> {
     contents: &'a str,
     remaining: &'a str
@@ -118,7 +580,7 @@ struct ParseResult<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::{ Tokenizer, Token };
+    use super::{ Tokenizer, TokenizerBuilder, Token, render_tokens };
 
     fn tokenizer_case(input: &str, expected: Vec<Token>) {
         let tokenizer = Tokenizer::default();
@@ -128,6 +590,260 @@ mod tests {
         assert_eq!(actual_tokens, expected);
     }
     
+    #[test]
+    fn exposes_default_sets() {
+        let tokenizer = Tokenizer::default();
+
+        let mut singletons: Vec<char> = tokenizer.singletons().iter().cloned().collect();
+        singletons.sort();
+
+        let mut separators: Vec<char> = tokenizer.separators().iter().cloned().collect();
+        separators.sort();
+
+        assert_eq!(singletons, vec!['#', '(', ')', ',', ':', '[', ']', '{', '}']);
+        assert_eq!(separators, vec!['\t', '\n', '\r', ' ']);
+    }
+
+    #[test]
+    fn keeps_leading_separators() {
+        let input = "   hello";
+
+        let expected_tokens = vec![
+            Token::new("", "   "),
+            Token::new("hello", "")
+        ];
+
+        tokenizer_case(input, expected_tokens);
+        assert_eq!(render_tokens(&Tokenizer::default().tokenize(input)), input);
+    }
+
+    #[test]
+    fn folds_comments_into_suffixes() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.set_line_comment(Some("#"));
+
+        let input = "# heading\na b # trailing\nc#d";
+
+        assert_eq!(tokenizer.tokenize(input), vec![
+            Token::new("", "# heading\n"),
+            Token::new("a", " "),
+            Token::new("b", " # trailing\n"),
+            Token::new("c", "#d")
+        ]);
+
+        assert_eq!(render_tokens(&tokenizer.tokenize(input)), input);
+    }
+
+    #[test]
+    fn drops_comments() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.set_line_comment(Some("//"));
+        tokenizer.set_drop_comments(true);
+
+        let tokens = tokenizer.tokenize("a // one\n  // two\nb // three");
+
+        assert_eq!(tokens, vec![
+            Token::new("a", "\n"),
+            Token::new("b", "")
+        ]);
+
+        assert_eq!((tokens[1].line, tokens[1].column), (3, 1));
+    }
+
+    #[test]
+    fn ignores_comments_in_quotes() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.set_line_comment(Some("#"));
+        tokenizer.extend_quote_chars(vec!['"']);
+
+        assert_eq!(tokenizer.tokenize("x \"a # b\" # c"), vec![
+            Token::new("x", " "),
+            Token::new("\"a # b\"", " # c")
+        ]);
+    }
+
+    fn escaping_tokenizer() -> Tokenizer {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.set_escape_char(Some('\\'));
+
+        tokenizer
+    }
+
+    #[test]
+    fn escapes_singletons_and_separators() {
+        let tokenizer = escaping_tokenizer();
+        let tokenizer_case = |input, expected: Vec<Token>| assert_eq!(tokenizer.tokenize(input), expected);
+
+        tokenizer_case("a \\{b\\} \\: c\\ d", vec![
+            Token::new("a", " "),
+            Token::new("\\{b\\}", " "),
+            Token::new("\\:", " "),
+            Token::new("c\\ d", "")
+        ]);
+
+        //Line breaks are not escaped, so template lines can still be continued
+        tokenizer_case("a \\\nb \\\r\nc", vec![
+            Token::new("a", " "),
+            Token::new("\\", "\n"),
+            Token::new("b", " "),
+            Token::new("\\", "\r\n"),
+            Token::new("c", "")
+        ]);
+
+        tokenizer_case("a\\\\(b) c\\", vec![
+            Token::new("a\\\\", ""),
+            Token::new("(", ""),
+            Token::new("b", ""),
+            Token::new(")", " "),
+            Token::new("c\\", "")
+        ]);
+
+        let mut tokenizer = escaping_tokenizer();
+        tokenizer.extend_quote_chars(vec!['"']);
+
+        assert_eq!(tokenizer.tokenize("\"a\\\" b\" \\\"c"), vec![
+            Token::new("\"a\\\" b\"", " "),
+            Token::new("\\\"c", "")
+        ]);
+
+        //Escaping is off by default
+        assert_eq!(Tokenizer::default().tokenize("a\\)"), vec![
+            Token::new("a\\", ""),
+            Token::new(")", "")
+        ]);
+    }
+
+    #[test]
+    fn reads_config() {
+        let config = "# A C-like language\n\
+            singletons = ( ) { } ;\n\
+            separators = \\s \\t \\n \\r\n\
+            \n\
+            multi_singletons = == ->\n\
+            quotes = \"\n\
+            line_comment = //\n";
+
+        let tokenizer = Tokenizer::parse_config(config).unwrap();
+        assert!(!tokenizer.drop_comments());
+
+        assert_eq!(tokenizer.tokenize("f(a==b);\t\"x y\" // done\n}"), vec![
+            Token::new("f", ""),
+            Token::new("(", ""),
+            Token::new("a", ""),
+            Token::new("==", ""),
+            Token::new("b", ""),
+            Token::new(")", ""),
+            Token::new(";", "\t"),
+            Token::new("\"x y\"", " // done\n"),
+            Token::new("}", "")
+        ]);
+
+        //Nothing is kept from the default tokenizer
+        assert!(!tokenizer.singletons().contains(&'#'));
+        assert_eq!(tokenizer.escape_char(), None);
+
+        let tokenizer = Tokenizer::parse_config("escape = ^").unwrap();
+        assert_eq!(tokenizer.escape_char(), Some('^'));
+
+        let tokenizer = Tokenizer::parse_config("escape =").unwrap();
+        assert_eq!(tokenizer.escape_char(), None);
+
+        let tokenizer = Tokenizer::parse_config("line_comment = //\ndrop_comments = true").unwrap();
+        assert!(tokenizer.drop_comments());
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        let error_for = |config| Tokenizer::parse_config(config).err().unwrap().to_string();
+
+        assert_eq!(error_for("singletons = ;\nseperators = \\s"), "Unknown tokenizer setting `seperators` on line 2 of the tokenizer config");
+        assert_eq!(error_for("separators \\s"), "Expected `key = items` but found `separators \\s` on line 1 of the tokenizer config");
+        assert_eq!(error_for("separators = \\x"), "Unknown escape `\\x` on line 1 of the tokenizer config");
+        assert_eq!(error_for("singletons = ab"), "`ab` is not a single character on line 1 of the tokenizer config");
+        assert_eq!(error_for("line_comment = // #"), "Expected a single line comment prefix on line 1 of the tokenizer config");
+        assert_eq!(error_for("drop_comments = yes"), "Expected `true` or `false` on line 1 of the tokenizer config");
+    }
+
+    #[test]
+    fn builds_on_defaults() {
+        let tokenizer = TokenizerBuilder::default().singleton(';').build();
+
+        assert_eq!(tokenizer.tokenize("a;b (c)"), vec![
+            Token::new("a", ""),
+            Token::new(";", ""),
+            Token::new("b", " "),
+            Token::new("(", ""),
+            Token::new("c", ""),
+            Token::new(")", "")
+        ]);
+
+        let tokenizer = TokenizerBuilder::new().separators("_-".chars()).build();
+
+        assert_eq!(tokenizer.tokenize("a_b (c)"), vec![
+            Token::new("a", "_"),
+            Token::new("b (c)", "")
+        ]);
+
+        let tokenizer = TokenizerBuilder::default()
+            .multi_singletons(vec!["->".to_string()])
+            .quote_chars("'".chars())
+            .line_comment(Some("//".to_string()))
+            .drop_comments(true)
+            .build();
+
+        assert_eq!(tokenizer.tokenize("a->'b c' // d\ne"), vec![
+            Token::new("a", ""),
+            Token::new("->", ""),
+            Token::new("'b c'", "\n"),
+            Token::new("e", "")
+        ]);
+    }
+
+    #[test]
+    fn reads_tokens_lazily() {
+        let tokenizer = Tokenizer::default();
+        let input = " a (b, c)\n d";
+
+        assert_eq!(tokenizer.tokens(input).collect::<Vec<Token>>(), tokenizer.tokenize(input));
+
+        let mut tokens = tokenizer.tokens(input);
+        let first_two: Vec<Token> = tokens.by_ref().take(2).collect();
+
+        assert_eq!(first_two, vec![Token::new("", " "), Token::new("a", " ")]);
+        assert_eq!(tokens.remaining, "(b, c)\n d");
+    }
+
+    #[test]
+    fn renders_tokens_back_to_input() {
+        let tokenizer = Tokenizer::default();
+
+        for input in &["", " ", "\n\t lead", "a b", "f(x, y) {\r\n  z;\n}\n", "trail  "] {
+            assert_eq!(render_tokens(&tokenizer.tokenize(input)), *input);
+        }
+    }
+
+    #[test]
+    fn tracks_positions() {
+        let tokenizer = Tokenizer::default();
+
+        let positions: Vec<(&str, u32, u32)> = tokenizer.tokenize("fn a(b)\n\tc;\r\n\n  \t}")
+            .iter()
+            .map(|token| (token.value, token.line, token.column))
+            .collect();
+
+        assert_eq!(positions, vec![
+            ("fn", 1, 1),
+            ("a", 1, 4),
+            ("(", 1, 5),
+            ("b", 1, 6),
+            (")", 1, 7),
+            ("c;", 2, 2),
+            ("}", 4, 4)
+        ]);
+
+        assert_eq!(Token::new("x", ""), tokenizer.tokenize("x")[0]);
+    }
+
     #[test]
     fn keeps_singletons_separate() {
         let input = "{[,]}";
@@ -156,6 +872,166 @@ mod tests {
         tokenizer_case(input, expected_tokens);
     }
     
+    #[test]
+    fn keeps_final_token() {
+        let input = "a bc";
+
+        let expected_tokens = vec![
+            Token::new("a", " "),
+            Token::new("bc", "")
+        ];
+
+        tokenizer_case(input, expected_tokens);
+    }
+
+    #[test]
+    fn handles_multibyte_characters() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.extend_singletons("«»、".chars());
+        tokenizer.extend_separators(Some('\u{3000}'));
+
+        let expected_tokens = vec![
+            Token::new("функция", " "),
+            Token::new("«", ""),
+            Token::new("変数", ""),
+            Token::new("、", "\u{3000}"),
+            Token::new("ä", ""),
+            Token::new("»", "")
+        ];
+
+        assert_eq!(tokenizer.tokenize("функция «変数、\u{3000}ä»"), expected_tokens);
+    }
+
+    fn arrow_tokenizer() -> Tokenizer {
+        let default = Tokenizer::default();
+        let multi_singletons = ["->", "->>"].iter().map(|singleton| singleton.to_string()).collect();
+
+        Tokenizer::new(default.singletons().clone(), default.separators().clone(), multi_singletons)
+    }
+
+    #[test]
+    fn splits_multi_char_singletons() {
+        let tokenizer = arrow_tokenizer();
+
+        assert_eq!(tokenizer.tokenize("a->b"), vec![
+            Token::new("a", ""),
+            Token::new("->", ""),
+            Token::new("b", "")
+        ]);
+
+        //The longest singleton wins
+        assert_eq!(tokenizer.tokenize("a->>b"), vec![
+            Token::new("a", ""),
+            Token::new("->>", ""),
+            Token::new("b", "")
+        ]);
+
+        assert_eq!(tokenizer.tokenize("a->"), vec![
+            Token::new("a", ""),
+            Token::new("->", "")
+        ]);
+
+        assert_eq!(tokenizer.tokenize("a ->b"), vec![
+            Token::new("a", " "),
+            Token::new("->", ""),
+            Token::new("b", "")
+        ]);
+
+        assert_eq!(tokenizer.tokenize("a-b >"), vec![
+            Token::new("a-b", " "),
+            Token::new(">", "")
+        ]);
+    }
+
+    #[test]
+    fn keeps_string_literals_whole() {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.extend_quote_chars("\"'".chars());
+
+        assert_eq!(tokenizer.tokenize("say(\"hello {world}\", 'a \"b\"') end"), vec![
+            Token::new("say", ""),
+            Token::new("(", ""),
+            Token::new("\"hello {world}\"", ""),
+            Token::new(",", " "),
+            Token::new("'a \"b\"'", ""),
+            Token::new(")", " "),
+            Token::new("end", "")
+        ]);
+
+        assert_eq!(tokenizer.tokenize("x=\"a b"), vec![
+            Token::new("x=", ""),
+            Token::new("\"a b", "")
+        ]);
+    }
+
+    /// Returns a description of how tokenizing input breaks the tokenizer's invariants, if it does
+    fn invariant_violation(tokenizer: &Tokenizer, input: &str) -> Option<String> {
+        let tokens = tokenizer.tokenize(input);
+
+        let reconstructed = render_tokens(&tokens);
+
+        if reconstructed != input {
+            return Some(format!("reconstructed as {:?}", reconstructed));
+        }
+
+        //Only leading separators may be held by a token without a value
+        tokens.iter().skip(1)
+            .position(|token| token.value.is_empty())
+            .map(|index| format!("token {} has an empty value in {:?}", index + 1, tokens))
+    }
+
+    /// Removes characters from a failing input for as long as it keeps failing
+    fn shrink(tokenizer: &Tokenizer, input: &str) -> String {
+        let mut chars: Vec<char> = input.chars().collect();
+        let mut index = 0;
+
+        while index < chars.len() {
+            let mut candidate = chars.clone();
+            candidate.remove(index);
+
+            if invariant_violation(tokenizer, &candidate.iter().collect::<String>()).is_some() {
+                chars = candidate;
+            } else {
+                index += 1;
+            }
+        }
+
+        chars.into_iter().collect()
+    }
+
+    #[test]
+    fn round_trips_generated_input() {
+        let tokenizer = Tokenizer::default();
+        let alphabet: Vec<char> = "{}[](),:# \n\r\tab_1-+$.é変".chars().collect();
+
+        //A fixed xorshift generator keeps failures reproducible
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+
+        for _ in 0 .. 2000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let len = (state % 16) as usize;
+            let mut input = String::new();
+
+            for _ in 0 .. len {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                input.push(alphabet[(state % alphabet.len() as u64) as usize]);
+            }
+
+            if let Some(violation) = invariant_violation(&tokenizer, &input) {
+                let minimal = shrink(&tokenizer, &input);
+
+                panic!("Tokenizing {:?} failed ({}), minimal failing input {:?}: {}",
+                    input, violation, minimal, invariant_violation(&tokenizer, &minimal).unwrap());
+            }
+        }
+    }
+
     #[test]
     fn function_like() {
         let input = "fn a(b: C, d: E) -> F { b + d }";
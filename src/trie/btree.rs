@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fmt::{ self, Debug, Formatter };
+
+use super::map::{ EdgeMap, Edges, MapTrie, TrieNode };
+
+/// A Trie/TrieMut implementor like HashTrie, but storing all nodes
+/// in a single BTreeMap so that its entries come out in sorted order
+pub type BTreeTrie<K, V> = MapTrie<BTreeTrieMap<K, V>>;
+
+/// Represents a mapping from edges, as the previous node and the key followed, to nodes.
+/// Edges are keyed by the node they come from first, so the children of a node are adjacent and sorted.
+pub type BTreeTrieMap<K, V> = BTreeMap<(u32, K), BTreeTrieNode<V>>;
+
+/// A node in the BTreeTrie
+pub type BTreeTrieNode<V> = TrieNode<V>;
+
+impl<K, V> EdgeMap for BTreeTrieMap<K, V>
+    where
+        K: Ord + Clone {
+
+    type Key = K;
+    type Value = V;
+    type Edge = (u32, K);
+
+    fn edge(prev_node: u32, key: K) -> (u32, K) {
+        (prev_node, key)
+    }
+
+    fn edge_parts(edge: &(u32, K)) -> (u32, &K) {
+        (edge.0, &edge.1)
+    }
+
+    fn node(&self, edge: &(u32, K)) -> Option<&BTreeTrieNode<V>> {
+        self.get(edge)
+    }
+
+    fn node_mut(&mut self, edge: &(u32, K)) -> Option<&mut BTreeTrieNode<V>> {
+        self.get_mut(edge)
+    }
+
+    fn insert_node(&mut self, edge: (u32, K), node: BTreeTrieNode<V>) {
+        self.insert(edge, node);
+    }
+
+    fn remove_node(&mut self, edge: &(u32, K)) -> Option<BTreeTrieNode<V>> {
+        self.remove(edge)
+    }
+
+    fn is_empty(&self) -> bool {
+        BTreeMap::is_empty(self)
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self);
+    }
+
+    fn edges(&self) -> Edges<'_, (u32, K), V> {
+        Box::new(self.iter())
+    }
+}
+
+/// Lists the path to each value in sorted order, rather than the edges and node ids that store them
+impl<K, V> Debug for BTreeTrie<K, V>
+    where
+        K: Ord + Clone + Debug,
+        V: Debug {
+
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BTreeTrie::Trivial { value } => f.debug_struct("Trivial").field("value", value).finish(),
+
+            BTreeTrie::Standard { .. } => {
+                write!(f, "Standard ")?;
+
+                f.debug_map().entries(self.entries()).finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trie::{ Trie, TrieMut, TrieView };
+
+    #[test]
+    fn insert_get() {
+        let mut btree_trie = BTreeTrie::new();
+
+        assert!(btree_trie.insert(vec!["A", "B"], 1));
+        assert!(btree_trie.insert(vec!["A", "C"], 2));
+        assert!(!btree_trie.insert(vec!["A"], 3));
+        assert!(!btree_trie.insert(vec!["A", "B", "C"], 4));
+
+        assert_eq!(btree_trie.get(vec!["A", "B"]).unwrap().value(), Some(&1));
+        assert_eq!(btree_trie.get(vec!["A", "C"]).unwrap().value(), Some(&2));
+        assert_eq!(btree_trie.get(vec!["A"]).unwrap().value(), None);
+        assert!(btree_trie.get(vec!["B"]).is_none());
+        assert!(btree_trie.contains_key(&["A", "B"]));
+        assert_eq!(btree_trie.len(), 2);
+    }
+
+    #[test]
+    fn iterates_in_sorted_order() {
+        let mut paths = vec![
+            vec!["pear"],
+            vec!["apple", "tart"],
+            vec!["fig", "b"],
+            vec!["apple", "pie"],
+            vec!["fig", "a", "z"],
+            vec!["banana"]
+        ];
+
+        let btree_trie: BTreeTrie<_, _> = paths.iter()
+            .map(|path| (path.clone(), path.join(" ")))
+            .collect();
+
+        paths.sort();
+
+        let iterated: Vec<Vec<&str>> = btree_trie.iter()
+            .map(|(path, _)| path.into_iter().cloned().collect())
+            .collect();
+
+        assert_eq!(iterated, paths);
+
+        let values: Vec<&String> = btree_trie.iter().map(|(_, value)| value).collect();
+
+        assert_eq!(values, vec!["apple pie", "apple tart", "banana", "fig a z", "fig b", "pear"]);
+    }
+
+    #[test]
+    fn root_value() {
+        let mut btree_trie = BTreeTrie::new();
+
+        assert!(btree_trie.insert(Vec::<u8>::new(), 1));
+        assert!(!btree_trie.insert(vec![1], 2));
+
+        assert_eq!(btree_trie.entries(), vec![(Vec::new(), &1)]);
+        assert_eq!(format!("{:?}", btree_trie), "Trivial { value: 1 }");
+    }
+
+    #[test]
+    fn debug_lists_sorted_paths() {
+        let btree_trie: BTreeTrie<_, _> = vec![(vec![2, 1], 'b'), (vec![1], 'a'), (vec![2, 0], 'c')]
+            .into_iter()
+            .collect();
+
+        assert_eq!(format!("{:?}", btree_trie), "Standard {[1]: 'a', [2, 0]: 'c', [2, 1]: 'b'}");
+    }
+}
@@ -1,52 +1,31 @@
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::fmt::{ self, Debug, Formatter };
 
-use super::{ Trie, TrieMut, TrieView, TrieViewMut };
+use super::map::{ self, EdgeMap, Edges, MapTrie, MapTrieView, TrieNode };
+#[cfg(feature = "serde")]
+use super::TrieMut;
 
 /// A Trie/TrieMut implementor, that stores all nodes
 /// in a single HashMap
-pub enum HashTrie<K, V>
-    where 
-        K: Hash + Eq {
-
-    /// A Trivial HashTrie is one whose root (representing the empty sequence)
-    /// is mapped to a value. 
-    /// Since the empty sequence is a prefix of all other sequences,
-    /// no other data can be stored.
-    Trivial {
-        value: V
-    },
-
-    /// A Standard HashTrie is any non-trivial one, including the empty Trie.
-    /// Nodes are represented by unsigned integers (with the root being zero),
-    /// and contains a mapping from edges to nodes, as well as the next unused node id.
-    Standard {
-        map: HashTrieMap<K, V>,
-        next_id: u32
-    }   
-}
-
-impl<K, V> HashTrie<K, V>
-    where 
-        K: Hash + Eq + Clone {
-    
-    /// Constructs an empty HashTrie
-    pub fn new() -> Self {
-        HashTrie::Standard {
-            map: HashMap::new(),
-            //1 is the next_id of empty HashTries, because 0 is reserved for the root
-            next_id: 1  
-        }
-    }
-}
+pub type HashTrie<K, V> = MapTrie<HashTrieMap<K, V>>;
 
 /// Represents a mapping from Edges to Nodes
 pub type HashTrieMap<K, V> = HashMap<HashTrieEdge<K>, HashTrieNode<V>>;
 
+/// A node in the HashTrie
+pub type HashTrieNode<V> = TrieNode<V>;
+
+/// A read-only view of a HashTrie
+pub type HashTrieView<'a, K, V> = MapTrieView<'a, HashTrieMap<K, V>>;
+
+/// The place for a value at the end of a path, found by HashTrie::entry
+pub type Entry<'a, K, V> = map::Entry<'a, HashTrieMap<K, V>>;
+
 /// An edge uniquely identifies the node it points to,
 /// by indicating the node it is coming from and the key
 /// that is followed to get there
-#[derive(Hash, Eq, PartialEq, Clone)]
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct HashTrieEdge<K>
     where
         K: Hash + Eq {
@@ -55,343 +34,140 @@ pub struct HashTrieEdge<K>
     edge_key: K
 }
 
-/// A node in the HashTrie
-pub enum HashTrieNode<V> {
-    /// A Branch has an id so that it can be represented as
-    /// the previous_node for edges.
-    /// It does not have a value because that would 
-    /// violate the prefix-free property
-    Branch {
-        id: u32
-    },
-    
-    /// A Leaf has a value that it holds, but has no id
-    /// because Leafs are never the previous_node for an edge.
-    Leaf {
-        value: V
-    }
-}
-
-impl<'a, K, V> Trie<K, V> for &'a HashTrie<K, V>
-    where 
+impl<K, V> EdgeMap for HashTrieMap<K, V>
+    where
         K: Hash + Eq + Clone {
 
-    type View = HashTrieView<'a, K, V>;
+    type Key = K;
+    type Value = V;
+    type Edge = HashTrieEdge<K>;
 
-    fn as_view(self) -> HashTrieView<'a, K, V> {
-        HashTrieView::new(self)
+    fn edge(prev_node: u32, key: K) -> HashTrieEdge<K> {
+        HashTrieEdge { prev_node, edge_key: key }
     }
-}
 
-impl<'a, K, V> Trie<K, V> for &'a mut HashTrie<K, V>
-    where 
-        K: Hash + Eq + Clone {
-
-    type View = HashTrieView<'a, K, V>;
+    fn edge_parts(edge: &HashTrieEdge<K>) -> (u32, &K) {
+        (edge.prev_node, &edge.edge_key)
+    }
 
-    fn as_view(self) -> HashTrieView<'a, K, V> {
-        HashTrieView::new(self)
+    fn node(&self, edge: &HashTrieEdge<K>) -> Option<&HashTrieNode<V>> {
+        self.get(edge)
     }
-}
 
-impl<'a, K, V> TrieMut<K, V> for &'a mut HashTrie<K, V>
-    where 
-        K: Hash + Eq + Clone {
+    fn node_mut(&mut self, edge: &HashTrieEdge<K>) -> Option<&mut HashTrieNode<V>> {
+        self.get_mut(edge)
+    }
 
-    type ViewMut = HashTrieViewMut<'a, K, V>;
+    fn insert_node(&mut self, edge: HashTrieEdge<K>, node: HashTrieNode<V>) {
+        self.insert(edge, node);
+    }
 
-    fn as_view_mut(self) -> HashTrieViewMut<'a, K, V> {
-        HashTrieViewMut::new(self)
+    fn remove_node(&mut self, edge: &HashTrieEdge<K>) -> Option<HashTrieNode<V>> {
+        self.remove(edge)
     }
-}
 
-/// A read-only view of a HashTrie
-pub struct HashTrieView<'a, K, V>
-    where
-        K: Hash + Eq {
+    fn is_empty(&self) -> bool {
+        HashMap::is_empty(self)
+    }
 
-    /// The HashTrie being Viewed
-    trie: &'a HashTrie<K, V>,
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
 
-    /// The Some edge leading to the current node,
-    /// or None if the current node is the root
-    edge: Option<HashTrieEdge<K>>
+    fn edges(&self) -> Edges<'_, HashTrieEdge<K>, V> {
+        Box::new(self.iter())
+    }
 }
 
-impl<'a, K, V> HashTrieView<'a, K, V>
+impl<K, V> HashTrie<K, V>
     where
-        K: Hash + Eq {
+        K: Hash + Eq + Clone {
 
-    fn new(hash_trie: &'a HashTrie<K,V>) -> Self {
-        HashTrieView {
-            trie: hash_trie,
-            edge: None  //Indicates that the current node is the root
+    /// Reserves room for at least additional more nodes, each key of a path being one node.
+    /// A Trivial HashTrie cannot hold any more, so reserving does nothing.
+    pub fn reserve(&mut self, additional: usize) {
+        if let HashTrie::Standard { map, .. } = self {
+            map.reserve(additional);
         }
     }
 }
 
-impl<'a, K, V> TrieView<K, V> for HashTrieView<'a, K, V> 
-    where K: Eq + Hash + Clone {
+/// Lists the path to each value, rather than the edges and node ids that store them.
+/// Paths are sorted by how they are printed, since the nodes are in no particular order.
+impl<K, V> Debug for HashTrie<K, V>
+    where
+        K: Hash + Eq + Clone + Debug,
+        V: Debug {
 
-    fn value(&self) -> Option<&V> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            HashTrieView {
-                trie: HashTrie::Trivial {
-                    value
-                },
-                edge: None  //Indicates current node is root
-            } => {
-                Some(&value)
-            },
+            HashTrie::Trivial { value } => f.debug_struct("Trivial").field("value", value).finish(),
 
-            HashTrieView {
-                trie: HashTrie::Standard { map, .. },
-                edge: Some(last_edge)
-            } => {
-                if let Some(HashTrieNode::Leaf { value }) = map.get(last_edge) {
-                    Some(&value)
-                } else {
-                    None
-                }
-            },
+            HashTrie::Standard { .. } => {
+                let mut entries: Vec<(String, &V)> = self.entries()
+                    .into_iter()
+                    .map(|(path, value)| (format!("{:?}", path), value))
+                    .collect();
 
-            _ => None
-        }
-    }
+                entries.sort_by(|(path_a, _), (path_b, _)| path_a.cmp(path_b));
 
-    fn descend(&self, key: K) -> Option<Self> {
-        match self {
-            HashTrieView { 
-                trie: HashTrie::Standard { map, .. }, 
-                edge: None  //Indicates current node is root
-            } => {
-                let next_edge = HashTrieEdge {
-                    prev_node: 0,   //Set previous node to 0
-                    edge_key: key
-                };
-
-                Some(HashTrieView { 
-                    trie: self.trie, 
-                    edge: Some(next_edge)
-                })
-            },
+                write!(f, "Standard ")?;
 
-            HashTrieView { 
-                trie: HashTrie::Standard { map, .. }, 
-                edge: Some(last_edge)
-            } => {
-                if let Some(HashTrieNode::Branch { id }) = map.get(last_edge) {
-                    let next_edge = HashTrieEdge {
-                        prev_node: *id,
-                        edge_key: key.clone()
-                    };
-
-                    Some(HashTrieView { 
-                        trie: self.trie, 
-                        edge: Some(next_edge)
-                    })
-                } else {
-                    None
+                let mut map = f.debug_map();
+
+                for (path, value) in &entries {
+                    map.entry(&format_args!("{}", path), value);
                 }
-            },
 
-            _ => None
+                map.finish()
+            }
         }
     }
 }
 
-/// A mutable view of a HashTrie
-pub struct HashTrieViewMut<'a, K, V>
+/// Serializes as a list of each path with its value, leaving node ids out of the format
+#[cfg(feature = "serde")]
+impl<K, V> serde::Serialize for HashTrie<K, V>
     where
-        K: Hash + Eq {
-            
-    /// The HashTrie being Viewed
-    trie: &'a mut HashTrie<K, V>,
+        K: Hash + Eq + Clone + serde::Serialize,
+        V: serde::Serialize {
 
-    /// The Some edge leading to the current node,
-    /// or None if the current node is the root
-    edge: Option<HashTrieEdge<K>>
-}
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer {
 
-impl<'a, K, V> HashTrieViewMut<'a, K, V>
-    where
-        K: Hash + Eq {
-
-    fn new(hash_trie: &'a mut HashTrie<K,V>) -> Self {
-        HashTrieViewMut {
-            trie: hash_trie,
-            edge: None  //Indicates that the current node is the root
-        }
+        serializer.collect_seq(self.entries())
     }
 }
 
-impl<'a, K, V> TrieViewMut<K, V> for HashTrieViewMut<'a, K, V> 
-    where K: Eq + Hash + Clone {
-
-    fn value(&mut self) -> Option<&mut V> {
-        match self {
-            HashTrieViewMut {
-                trie: HashTrie::Trivial {
-                    value
-                },
-                edge: None  //Indicates current node is root
-            } => {
-                Some(value)
-            },
-
-            HashTrieViewMut {
-                trie: HashTrie::Standard { map, .. },
-                edge: Some(last_edge)
-            } => {
-                if let Some(HashTrieNode::Leaf { value }) = map.get_mut(last_edge) {
-                    Some(value)
-                } else {
-                    None
-                }
-            },
-
-            _ => None
-        }
-    }
-    
-    fn set_value(&mut self, new_value: V) -> bool {
-        let make_trivial;
+/// Inserts each serialized path and value in turn, so node ids are assigned afresh
+#[cfg(feature = "serde")]
+impl<'de, K, V> serde::Deserialize<'de> for HashTrie<K, V>
+    where
+        K: Hash + Eq + Clone + serde::Deserialize<'de>,
+        V: serde::Deserialize<'de> {
 
-        match self {
-            HashTrieViewMut {
-                trie: HashTrie::Standard { map, .. },
-                edge: None  //Indicates current node is root
-            } => {
-                make_trivial = map.is_empty();
-            },
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de> {
 
-            HashTrieViewMut {
-                trie: HashTrie::Standard { map, .. },
-                edge: Some(last_edge)
-            } => {
-                match map.get_mut(last_edge) {
-                    None => {
-                        map.insert(last_edge.clone(), HashTrieNode::Leaf { 
-                            value: new_value 
-                        });
-                        return true;
-                    },
-
-                    Some(HashTrieNode::Leaf { value }) => {
-                        *value = new_value;
-                        return true;
-                    }
-
-                    _ => {
-                        return false;
-                    }
-                }
-            },
+        let entries: Vec<(Vec<K>, V)> = serde::Deserialize::deserialize(deserializer)?;
+        let mut hash_trie = HashTrie::new();
 
-            _ => {
-                return false;
+        for (path, value) in entries {
+            if !hash_trie.insert(path, value) {
+                return Err(serde::de::Error::custom("HashTrie paths must be prefix free"));
             }
-        };
-
-        if make_trivial {
-            *self.trie = HashTrie::Trivial { value: new_value };
-            true
-        } else {
-            false
         }
-    }
-
-    fn descend(self, key: K) -> Option<Self> {
-        let mut self_alias = self;
-        let next_edge;
 
-        match &mut self_alias {
-            HashTrieViewMut { 
-                trie: HashTrie::Standard { .. }, 
-                edge: None
-            } => {
-                next_edge = HashTrieEdge {
-                    prev_node: 0,   //Indicating root node
-                    edge_key: key
-                };
-            },
-
-            HashTrieViewMut { 
-                trie: HashTrie::Standard { map, next_id }, 
-                edge: Some(ref last_edge)
-            } => {
-                if let Some(HashTrieNode::Branch { id }) = map.get(&last_edge) {
-                    next_edge = HashTrieEdge {
-                        prev_node: *id,
-                        edge_key: key
-                    };
-                } else {
-                    return None;
-                }
-            },
-
-            _ => {
-                return None;
-            }
-        };
-
-        Some(HashTrieViewMut { 
-            trie: self_alias.trie, 
-            edge: Some(next_edge)
-        })
-    }
-
-    fn descend_or_add(self, key: K) -> Option<Self> {
-        let mut self_alias = self;
-        let mut next_edge;
-
-        match &mut self_alias {
-            HashTrieViewMut { 
-                trie: HashTrie::Standard { .. }, 
-                edge: None
-            } => {
-                next_edge = HashTrieEdge {
-                    prev_node: 0,   //Indicating root node
-                    edge_key: key
-                };
-            },
-
-            HashTrieViewMut { 
-                trie: HashTrie::Standard { map, next_id }, 
-                edge: Some(ref last_edge)
-            } => {
-                if let Some(HashTrieNode::Branch { id }) = map.get(&last_edge) {
-                    next_edge = HashTrieEdge {
-                        prev_node: *id,
-                        edge_key: key
-                    };
-                } else {
-                    next_edge = HashTrieEdge {
-                        prev_node: *next_id,
-                        edge_key: key
-                    };
-                    
-                    map.insert(last_edge.clone(), HashTrieNode::Branch { id: *next_id });
-
-                    *next_id += 1;  //Will currently panic when overflow occurs
-                }
-            },
-
-            _ => {
-                return None;
-            }
-        };
-
-        Some(HashTrieViewMut { 
-            trie: self_alias.trie, 
-            edge: Some(next_edge)
-        })
+        Ok(hash_trie)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::trie::{ Trie, TrieMut, TrieView };
 
     #[test]
     fn insert_get() {
@@ -432,4 +208,510 @@ mod test {
 
         assert_eq!(hash_trie.get(keys_b).unwrap().value(), Some(&"B".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn insert_extending_leaf_fails() {
+        let mut hash_trie = HashTrie::new();
+
+        assert!(hash_trie.insert(vec!["A"], 1));
+        assert!(!hash_trie.insert(vec!["A", "B"], 2));
+
+        assert_eq!(hash_trie.get(vec!["A"]).unwrap().value(), Some(&1));
+    }
+
+    #[test]
+    fn to_dot() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B"], "x");
+
+        let dot = hash_trie.to_dot(|key| key.to_string(), |value| format!("\"{}\"", value));
+
+        assert!(dot.starts_with("digraph trie {\n"));
+        assert!(dot.contains("    n0 [shape=point];\n"));
+        assert!(dot.contains("    n1 [shape=point];\n"));
+        assert!(dot.contains("    n0 -> n1 [label=\"A\"];\n"));
+        assert!(dot.contains(" [shape=box, label=\"\\\"x\\\"\"];\n"));
+        assert!(dot.contains(" [label=\"B\"];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn get_missing_path() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B"], 1);
+
+        assert!(hash_trie.get(vec!["B"]).is_none());
+        assert!(hash_trie.get(vec!["A", "C"]).is_none());
+        assert!(hash_trie.get(vec!["A"]).unwrap().value().is_none());
+    }
+
+    #[test]
+    fn get_root_value() {
+        let mut hash_trie = HashTrie::new();
+
+        assert!(hash_trie.get(Vec::<&str>::new()).unwrap().value().is_none());
+
+        assert!(hash_trie.insert(Vec::<&str>::new(), 1));
+        assert_eq!(hash_trie.get(Vec::<&str>::new()).unwrap().value(), Some(&1));
+        assert!(hash_trie.get(vec!["A"]).is_none());
+
+        assert!(hash_trie.insert(Vec::<&str>::new(), 2));
+        assert_eq!(hash_trie.get(Vec::<&str>::new()).unwrap().value(), Some(&2));
+        assert!(!hash_trie.insert(vec!["A"], 3));
+    }
+
+    #[test]
+    fn remove_prunes_empty_branches() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B", "C"], 1);
+        hash_trie.insert(vec!["A", "D"], 2);
+
+        assert_eq!(hash_trie.remove(vec!["A", "B"]), None);
+        assert_eq!(hash_trie.remove(vec!["A", "D", "E"]), None);
+        assert_eq!(hash_trie.remove(vec!["A", "B", "C"]), Some(1));
+        assert_eq!(hash_trie.remove(vec!["A", "B", "C"]), None);
+
+        assert_eq!(hash_trie.get(vec!["A", "D"]).unwrap().value(), Some(&2));
+        assert!(hash_trie.get(vec!["A", "B"]).is_none());
+
+        //With the branch at [A, B] gone, a value can be stored there
+        assert!(hash_trie.insert(vec!["A", "B"], 3));
+        assert_eq!(hash_trie.entries().len(), 2);
+
+        assert_eq!(hash_trie.remove(vec!["A", "B"]), Some(3));
+        assert_eq!(hash_trie.remove(vec!["A", "D"]), Some(2));
+        assert!(hash_trie.entries().is_empty());
+        assert!(hash_trie.insert(vec!["A"], 4));
+    }
+
+    #[test]
+    fn remove_root_value() {
+        let mut hash_trie = HashTrie::new();
+
+        assert_eq!(hash_trie.remove(Vec::<&str>::new()), None);
+
+        hash_trie.insert(Vec::<&str>::new(), 1);
+
+        assert_eq!(hash_trie.remove(vec!["A"]), None);
+        assert_eq!(hash_trie.remove(Vec::<&str>::new()), Some(1));
+        assert!(hash_trie.insert(vec!["A"], 2));
+    }
+
+    #[test]
+    fn empty_trivial_standard_transitions() {
+        let mut hash_trie = HashTrie::new();
+        let root = Vec::<&str>::new;
+
+        //An empty trie is Standard, and becomes Trivial when its root takes a value
+        assert_eq!(format!("{:?}", hash_trie), "Standard {}");
+        assert!(hash_trie.insert(root(), 1));
+        assert_eq!(format!("{:?}", hash_trie), "Trivial { value: 1 }");
+
+        //Removing the root value leaves it empty again, ready for other paths
+        assert_eq!(hash_trie.remove(root()), Some(1));
+        assert_eq!(format!("{:?}", hash_trie), "Standard {}");
+        assert!(hash_trie.insert(vec!["A", "B"], 2));
+
+        //A non-empty Standard trie never becomes Trivial
+        assert!(!hash_trie.insert(root(), 3));
+        assert!(hash_trie.entry(root()).is_none());
+        assert_eq!(format!("{:?}", hash_trie), "Standard {[\"A\", \"B\"]: 2}");
+
+        //Until removing its last value prunes every branch
+        assert_eq!(hash_trie.remove(vec!["A", "B"]), Some(2));
+
+        match hash_trie.entry(root()) {
+            Some(Entry::Vacant(vacant)) => assert_eq!(*vacant.insert(4), 4),
+            _ => panic!("The emptied trie should have a vacant root")
+        }
+
+        assert_eq!(format!("{:?}", hash_trie), "Trivial { value: 4 }");
+
+        hash_trie.clear();
+        assert_eq!(format!("{:?}", hash_trie), "Standard {}");
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut hash_trie: HashTrie<u32, String> = HashTrie::new();
+
+        hash_trie.insert(vec![1, 2], "a".to_string());
+        hash_trie.insert(vec![3], "b".to_string());
+
+        let mut cloned = hash_trie.clone();
+
+        assert_eq!(format!("{:?}", cloned), format!("{:?}", hash_trie));
+
+        cloned.insert(vec![1, 4], "c".to_string());
+        *cloned.get_mut(vec![3]).unwrap() = "d".to_string();
+
+        assert_eq!(format!("{:?}", hash_trie), "Standard {[1, 2]: \"a\", [3]: \"b\"}");
+        assert_eq!(cloned.len(), 3);
+
+        let edge = HashTrieEdge { prev_node: 0, edge_key: "A".to_string() };
+        assert_eq!(edge.clone(), edge);
+    }
+
+    #[test]
+    fn clone_copies_ids_and_root_value() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B"], 1);
+
+        let mut cloned = hash_trie.clone();
+
+        //Both copies assign the same next id, without sharing it
+        assert!(hash_trie.insert(vec!["C", "D"], 2));
+        assert!(cloned.insert(vec!["C", "D"], 3));
+
+        if let (HashTrie::Standard { next_id, .. }, HashTrie::Standard { next_id: cloned_id, .. }) = (&hash_trie, &cloned) {
+            assert_eq!(next_id, cloned_id);
+        }
+
+        assert_eq!(hash_trie.get(vec!["C", "D"]).unwrap().value(), Some(&2));
+
+        let trivial = HashTrie::<&str, i32>::Trivial { value: 4 };
+        let mut cloned = trivial.clone();
+
+        assert_eq!(cloned.remove(Vec::new()), Some(4));
+        assert_eq!(format!("{:?}", trivial), "Trivial { value: 4 }");
+    }
+
+    #[test]
+    fn keys_and_values_match_iter() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["B", "C"], 2);
+        hash_trie.insert(vec!["A"], 1);
+        hash_trie.insert(vec!["B", "D", "E"], 3);
+
+        let mut keys: Vec<Vec<&&str>> = hash_trie.keys().collect();
+        let mut values: Vec<&i32> = hash_trie.values().collect();
+        keys.sort();
+        values.sort();
+
+        let (mut iter_keys, mut iter_values): (Vec<Vec<&&str>>, Vec<&i32>) = hash_trie.iter().unzip();
+        iter_keys.sort();
+        iter_values.sort();
+
+        assert_eq!(keys, iter_keys);
+        assert_eq!(values, iter_values);
+        assert_eq!(values, vec![&1, &2, &3]);
+
+        let trivial = HashTrie::<&str, i32>::Trivial { value: 4 };
+
+        assert_eq!(trivial.keys().collect::<Vec<Vec<&&str>>>(), vec![Vec::<&&str>::new()]);
+        assert_eq!(trivial.values().collect::<Vec<&i32>>(), vec![&4]);
+        assert_eq!(HashTrie::<&str, i32>::new().values().count(), 0);
+    }
+
+    #[test]
+    fn iter_yields_every_leaf_once() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["B", "C"], 2);
+        hash_trie.insert(vec!["A"], 1);
+        hash_trie.insert(vec!["B", "D", "E"], 3);
+
+        let mut pairs: Vec<(Vec<&&str>, &i32)> = hash_trie.iter().collect();
+        pairs.sort();
+
+        assert_eq!(pairs, vec![
+            (vec![&"A"], &1),
+            (vec![&"B", &"C"], &2),
+            (vec![&"B", &"D", &"E"], &3)
+        ]);
+
+        let trivial = HashTrie::Trivial { value: 4 };
+        assert_eq!(trivial.iter().collect::<Vec<(Vec<&&str>, &i32)>>(), vec![(Vec::new(), &4)]);
+    }
+
+    #[test]
+    fn insert_fails_when_out_of_ids() {
+        let mut hash_trie = HashTrie::Standard {
+            map: HashMap::new(),
+            next_id: u32::MAX - 1
+        };
+
+        assert!(hash_trie.insert(vec!["A", "B"], 1));
+        assert!(!hash_trie.insert(vec!["C", "D"], 2));
+        assert!(hash_trie.insert(vec!["E"], 3));
+
+        assert_eq!(hash_trie.get(vec!["A", "B"]).unwrap().value(), Some(&1));
+        assert!(hash_trie.get(vec!["C", "D"]).is_none());
+        assert_eq!(hash_trie.get(vec!["E"]).unwrap().value(), Some(&3));
+    }
+
+    #[test]
+    fn insert_out_of_ids_adds_nothing() {
+        let mut hash_trie = HashTrie::Standard {
+            map: HashMap::new(),
+            next_id: u32::MAX - 1
+        };
+
+        //Needs two new Branches, but only one id remains
+        assert!(!hash_trie.insert(vec!["A", "B", "C"], 1));
+
+        if let HashTrie::Standard { map, next_id } = &hash_trie {
+            assert!(map.is_empty());
+            assert_eq!(*next_id, u32::MAX - 1);
+        } else {
+            panic!("The trie should still be Standard");
+        }
+
+        assert!(hash_trie.insert(vec!["A", "B"], 2));
+        assert_eq!(hash_trie.get(vec!["A", "B"]).unwrap().value(), Some(&2));
+    }
+
+    #[test]
+    fn contains_key() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B"], 1);
+
+        assert!(hash_trie.contains_key(&["A", "B"]));
+        assert!(!hash_trie.contains_key(&["A"]));
+        assert!(!hash_trie.contains_key(&["A", "B", "C"]));
+        assert!(!hash_trie.contains_key(&["C"]));
+        assert!(!hash_trie.contains_key(&[]));
+
+        let trivial = HashTrie::Trivial { value: 2 };
+
+        assert!(trivial.contains_key(&[]));
+        assert!(!trivial.contains_key(&["A"]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let sorted = |hash_trie: &HashTrie<String, i32>| {
+            let mut entries: Vec<(Vec<String>, i32)> = hash_trie.iter()
+                .map(|(path, value)| (path.into_iter().cloned().collect(), *value))
+                .collect();
+
+            entries.sort();
+            entries
+        };
+
+        let standard: HashTrie<String, i32> = vec![
+            (vec!["A".to_string(), "B".to_string()], 1),
+            (vec!["A".to_string(), "C".to_string()], 2),
+            (vec!["D".to_string()], 3)
+        ].into_iter().collect();
+
+        let trivial = HashTrie::Trivial { value: 4 };
+
+        for hash_trie in &[standard, trivial] {
+            let json = serde_json::to_string(hash_trie).unwrap();
+            let read_back: HashTrie<String, i32> = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(sorted(&read_back), sorted(hash_trie));
+        }
+
+        assert!(serde_json::from_str::<HashTrie<String, i32>>(r#"[[["A"], 1], [["A", "B"], 2]]"#).is_err());
+    }
+
+    #[test]
+    fn get_mut_changes_in_place() {
+        let mut hash_trie = HashTrie::new();
+        hash_trie.insert(vec!["A", "B"], "one".to_string());
+
+        hash_trie.get_mut(vec!["A", "B"]).unwrap().push_str(" two");
+
+        assert_eq!(hash_trie.get(vec!["A", "B"]).unwrap().value(), Some(&"one two".to_string()));
+
+        assert!(hash_trie.get_mut(vec!["A"]).is_none());
+        assert!(hash_trie.get_mut(vec!["A", "B", "C"]).is_none());
+        assert!(hash_trie.get_mut(vec!["A", "C"]).is_none());
+        assert!(hash_trie.get_mut(vec![]).is_none());
+
+        let mut trivial = HashTrie::Trivial { value: 1 };
+        *trivial.get_mut(vec!["A"; 0]).unwrap() += 1;
+
+        assert_eq!(trivial.get(vec![]).unwrap().value(), Some(&2));
+    }
+
+    #[test]
+    fn debug_lists_paths() {
+        let hash_trie: HashTrie<&str, i32> = vec![
+            (vec!["A", "C"], 2),
+            (vec!["A", "B"], 1)
+        ].into_iter().collect();
+
+        assert_eq!(format!("{:?}", hash_trie), r#"Standard {["A", "B"]: 1, ["A", "C"]: 2}"#);
+        assert_eq!(format!("{:?}", HashTrie::<&str, i32>::new()), "Standard {}");
+        assert_eq!(format!("{:?}", HashTrie::<&str, i32>::Trivial { value: 3 }), "Trivial { value: 3 }");
+    }
+
+    #[test]
+    fn collect_pairs() {
+        let hash_trie: HashTrie<&str, i32> = vec![
+            (vec!["A", "B"], 1),
+            (vec!["A", "C"], 2),
+            (vec!["D"], 3),
+            (vec!["A", "B", "E"], 4)
+        ].into_iter().collect();
+
+        assert_eq!(hash_trie.get(vec!["A", "B"]).unwrap().value(), Some(&1));
+        assert_eq!(hash_trie.get(vec!["A", "C"]).unwrap().value(), Some(&2));
+        assert_eq!(hash_trie.get(vec!["D"]).unwrap().value(), Some(&3));
+
+        //Extending a path with a value is skipped, as by insert
+        assert!(hash_trie.get(vec!["A", "B", "E"]).is_none());
+        assert_eq!(hash_trie.len(), 3);
+    }
+
+    #[test]
+    fn entry_occupied_returns_existing() {
+        let mut hash_trie = HashTrie::new();
+        hash_trie.insert(vec!["A", "B"], 1);
+
+        match hash_trie.entry(vec!["A", "B"]) {
+            Some(Entry::Occupied(value)) => *value += 10,
+            _ => panic!("A path with a value should be occupied")
+        }
+
+        assert_eq!(hash_trie.get(vec!["A", "B"]).unwrap().value(), Some(&11));
+        assert_eq!(hash_trie.len(), 1);
+
+        let mut trivial = HashTrie::Trivial { value: 3 };
+
+        assert!(matches!(trivial.entry(vec![]), Some(Entry::Occupied(&mut 3))));
+        assert!(trivial.entry(vec!["A"]).is_none());
+    }
+
+    #[test]
+    fn entry_vacant_inserts_new() {
+        let mut hash_trie = HashTrie::new();
+        hash_trie.insert(vec!["A", "B"], 1);
+
+        for (path, value) in [(vec!["A", "C", "D"], 2), (vec!["E"], 3)] {
+            match hash_trie.entry(path) {
+                Some(Entry::Vacant(vacant)) => assert_eq!(*vacant.insert(value), value),
+                _ => panic!("A path sharing no more than a branch should be vacant")
+            }
+        }
+
+        assert_eq!(hash_trie.get(vec!["A", "C", "D"]).unwrap().value(), Some(&2));
+        assert_eq!(hash_trie.get(vec!["E"]).unwrap().value(), Some(&3));
+        assert_eq!(hash_trie.len(), 3);
+
+        //New branches get fresh ids, so later inserts through them still work
+        assert!(hash_trie.insert(vec!["A", "C", "F"], 4));
+        assert_eq!(hash_trie.get(vec!["A", "C", "D"]).unwrap().value(), Some(&2));
+
+        //Paths that would break prefix-freedom have no entry
+        assert!(hash_trie.entry(vec!["A", "B", "C"]).is_none());
+        assert!(hash_trie.entry(vec!["A", "C"]).is_none());
+        assert!(hash_trie.entry(vec![]).is_none());
+
+        let mut empty: HashTrie<&str, i32> = HashTrie::new();
+
+        match empty.entry(vec![]) {
+            Some(Entry::Vacant(vacant)) => assert_eq!(*vacant.insert(5), 5),
+            _ => panic!("An empty trie should have a vacant root")
+        }
+        assert_eq!(empty.get(vec![]).unwrap().value(), Some(&5));
+    }
+
+    #[test]
+    fn get_longest_prefix() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B"], 1);
+        hash_trie.insert(vec!["A", "C", "D"], 2);
+
+        assert_eq!(hash_trie.get_longest_prefix(&["A", "B", "X"]), Some((2, &1)));
+        assert_eq!(hash_trie.get_longest_prefix(&["A", "C", "D", "E"]), Some((3, &2)));
+        assert_eq!(hash_trie.get_longest_prefix(&["A", "C", "D"]), Some((3, &2)));
+
+        //Running out of input before reaching a value finds nothing
+        assert_eq!(hash_trie.get_longest_prefix(&["A", "C"]), None);
+        assert_eq!(hash_trie.get_longest_prefix(&["A"]), None);
+        assert_eq!(hash_trie.get_longest_prefix(&["X", "B"]), None);
+        assert_eq!(hash_trie.get_longest_prefix(&[]), None);
+
+        let trivial = HashTrie::Trivial { value: 3 };
+
+        assert_eq!(trivial.get_longest_prefix(&["A", "B"]), Some((0, &3)));
+    }
+
+    #[test]
+    fn clear_and_reserve() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B"], 1);
+        hash_trie.insert(vec!["C"], 2);
+        hash_trie.clear();
+
+        assert!(hash_trie.is_empty());
+        assert!(hash_trie.get(vec!["A", "B"]).is_none());
+
+        hash_trie.reserve(16);
+
+        match &hash_trie {
+            HashTrie::Standard { map, next_id } => {
+                assert!(map.capacity() >= 16);
+                assert_eq!(*next_id, 1);
+            },
+
+            HashTrie::Trivial { .. } => panic!("A cleared trie should stay a standard trie")
+        }
+
+        assert!(hash_trie.insert(vec!["A", "C"], 3));
+        assert_eq!(hash_trie.get(vec!["A", "C"]).unwrap().value(), Some(&3));
+        assert_eq!(hash_trie.len(), 1);
+
+        let mut trivial = HashTrie::Trivial { value: 4 };
+        trivial.reserve(16);
+        trivial.clear();
+
+        assert!(trivial.is_empty());
+        assert!(trivial.insert(vec!["A"], 5));
+    }
+
+    #[test]
+    fn len_counts_values() {
+        let mut hash_trie = HashTrie::new();
+
+        assert_eq!(hash_trie.len(), 0);
+        assert!(hash_trie.is_empty());
+
+        hash_trie.insert(vec!["A", "B"], 1);
+        hash_trie.insert(vec!["A", "C"], 2);
+        assert_eq!(hash_trie.len(), 2);
+
+        hash_trie.insert(vec!["A", "B"], 3);
+        assert_eq!(hash_trie.len(), 2);
+
+        hash_trie.insert(vec!["A"], 4);
+        assert_eq!(hash_trie.len(), 2);
+        assert!(!hash_trie.is_empty());
+
+        hash_trie.remove(vec!["A", "C"]);
+        assert_eq!(hash_trie.len(), 1);
+
+        assert_eq!(HashTrie::<&str, i32>::Trivial { value: 5 }.len(), 1);
+    }
+
+    #[test]
+    fn entries() {
+        let mut hash_trie = HashTrie::new();
+
+        hash_trie.insert(vec!["A", "B", "C"], 1);
+        hash_trie.insert(vec!["A", "D"], 2);
+        hash_trie.insert(vec!["E"], 3);
+
+        let mut entries = hash_trie.entries();
+        entries.sort_by_key(|(_, value)| **value);
+
+        assert_eq!(entries, vec![
+            (vec![&"A", &"B", &"C"], &1),
+            (vec![&"A", &"D"], &2),
+            (vec![&"E"], &3)
+        ]);
+    }
+}
@@ -0,0 +1,755 @@
+use std::collections::HashMap;
+use std::iter::FromIterator;
+
+use super::{ Trie, TrieMut, TrieView, TrieViewMut };
+
+/// The storage behind a MapTrie: a single map from edges to the nodes they lead to,
+/// where each edge is identified by the node it comes from and the key it follows
+pub trait EdgeMap: Default {
+    type Key;
+    type Value;
+    type Edge: Clone;
+
+    fn edge(prev_node: u32, key: Self::Key) -> Self::Edge;
+
+    /// The node an edge comes from and the key it follows
+    fn edge_parts(edge: &Self::Edge) -> (u32, &Self::Key);
+
+    fn node(&self, edge: &Self::Edge) -> Option<&TrieNode<Self::Value>>;
+
+    fn node_mut(&mut self, edge: &Self::Edge) -> Option<&mut TrieNode<Self::Value>>;
+
+    fn insert_node(&mut self, edge: Self::Edge, node: TrieNode<Self::Value>);
+
+    fn remove_node(&mut self, edge: &Self::Edge) -> Option<TrieNode<Self::Value>>;
+
+    fn is_empty(&self) -> bool;
+
+    fn clear(&mut self);
+
+    /// Iterates over every edge and the node it leads to, in the map's own order
+    fn edges(&self) -> Edges<'_, Self::Edge, Self::Value>;
+}
+
+/// The edges of an EdgeMap, each with the node it leads to
+pub type Edges<'m, E, V> = Box<dyn Iterator<Item=(&'m E, &'m TrieNode<V>)> + 'm>;
+
+/// The children of each node, by node id, as the key followed to each child and the child
+type Children<'t, K, V> = HashMap<u32, Vec<(&'t K, &'t TrieNode<V>)>>;
+
+/// A Trie/TrieMut implementor that stores all nodes in a single EdgeMap.
+/// HashTrie and BTreeTrie are MapTries over a HashMap and a BTreeMap.
+#[derive(Clone)]
+pub enum MapTrie<M>
+    where
+        M: EdgeMap {
+
+    /// A Trivial MapTrie is one whose root (representing the empty sequence)
+    /// is mapped to a value.
+    /// Since the empty sequence is a prefix of all other sequences,
+    /// no other data can be stored.
+    Trivial {
+        value: M::Value
+    },
+
+    /// A Standard MapTrie is any non-trivial one, including the empty Trie.
+    /// Nodes are represented by unsigned integers (with the root being zero),
+    /// and contains a mapping from edges to nodes, as well as the next unused node id.
+    Standard {
+        map: M,
+        next_id: u32
+    }
+}
+
+/// A node in a MapTrie
+#[derive(Clone)]
+pub enum TrieNode<V> {
+    /// A Branch has an id so that it can be represented as
+    /// the previous_node for edges.
+    /// It does not have a value because that would
+    /// violate the prefix-free property
+    Branch {
+        id: u32
+    },
+
+    /// A Leaf has a value that it holds, but has no id
+    /// because Leafs are never the previous_node for an edge.
+    Leaf {
+        value: V
+    }
+}
+
+impl<M> MapTrie<M>
+    where
+        M: EdgeMap {
+
+    /// Constructs an empty MapTrie
+    pub fn new() -> Self {
+        MapTrie::Standard {
+            map: M::default(),
+            //1 is the next_id of empty MapTries, because 0 is reserved for the root
+            next_id: 1
+        }
+    }
+
+    /// Whether a value is stored at exactly the end of path,
+    /// rather than at a prefix or extension of it
+    pub fn contains_key<'c, I>(&self, path: I) -> bool
+        where
+            I: IntoIterator<Item=&'c M::Key>,
+            M::Key: Clone + 'c {
+
+        self.get(path.into_iter().cloned()).is_some_and(|view| view.value().is_some())
+    }
+
+    /// The value at exactly the end of path, to be changed in place
+    pub fn get_mut<T>(&mut self, path: T) -> Option<&mut M::Value>
+        where
+            T: IntoIterator<Item=M::Key> {
+
+        let mut path = path.into_iter();
+
+        let map = match self {
+            MapTrie::Trivial { value } => {
+                return match path.next() {
+                    Some(_) => None,
+                    None => Some(value)
+                };
+            },
+
+            MapTrie::Standard { map, .. } => map
+        };
+
+        let mut edge = M::edge(0, path.next()?);
+
+        for key in path {
+            match map.node(&edge)? {
+                TrieNode::Branch { id } => edge = M::edge(*id, key),
+                TrieNode::Leaf { .. } => return None
+            }
+        }
+
+        match map.node_mut(&edge)? {
+            TrieNode::Leaf { value } => Some(value),
+            TrieNode::Branch { .. } => None
+        }
+    }
+
+    /// Finds the place for a value at the end of path, walking the path once,
+    /// so that it can be read or filled without walking it again.
+    /// There is no such place if path extends a path with a value,
+    /// is a prefix of other paths or would need more node ids than remain.
+    pub fn entry<T>(&mut self, path: T) -> Option<Entry<'_, M>>
+        where
+            T: IntoIterator<Item=M::Key> {
+
+        let mut path = path.into_iter();
+
+        let (map, next_id) = match self {
+            MapTrie::Trivial { value } => {
+                return match path.next() {
+                    Some(_) => None,
+                    None => Some(Entry::Occupied(value))
+                };
+            },
+
+            MapTrie::Standard { map, next_id } => (&*map, *next_id)
+        };
+
+        //Walking the path only reads the trie, so that the entry can borrow it afterwards
+        let mut node = 0;
+        let mut leaf_edge = None;
+
+        while let Some(key) = path.next() {
+            let edge = M::edge(node, key);
+
+            match map.node(&edge) {
+                Some(TrieNode::Branch { id }) => node = *id,
+
+                Some(TrieNode::Leaf { .. }) => {
+                    if path.next().is_some() {
+                        return None;
+                    }
+
+                    leaf_edge = Some(edge);
+                    break;
+                },
+
+                None => {
+                    //Every key but the last needs a new Branch
+                    let keys: Vec<M::Key> = path.collect();
+                    next_id.checked_add(keys.len() as u32)?;
+
+                    return Some(Entry::Vacant(VacantEntry { trie: self, first_edge: Some(edge), keys }));
+                }
+            }
+        }
+
+        match (leaf_edge, self) {
+            (Some(edge), MapTrie::Standard { map, .. }) => match map.node_mut(&edge) {
+                Some(TrieNode::Leaf { value }) => Some(Entry::Occupied(value)),
+                _ => None
+            },
+
+            //Only the root of an empty trie may take a value without any keys
+            (None, trie @ MapTrie::Standard { .. }) if node == 0 && trie.is_empty() => {
+                Some(Entry::Vacant(VacantEntry { trie, first_edge: None, keys: Vec::new() }))
+            },
+
+            //Otherwise the path ends at a Branch, which cannot also have a value
+            _ => None
+        }
+    }
+
+    /// Finds the value stored at the longest prefix of path,
+    /// along with the number of keys in that prefix.
+    /// Since a MapTrie is prefix-free at most one prefix can hold a value;
+    /// see OverlappingTrie::longest_match for tries where values nest.
+    pub fn get_longest_prefix<'c, I>(&self, path: I) -> Option<(usize, &M::Value)>
+        where
+            I: IntoIterator<Item=&'c M::Key>,
+            M::Key: Clone + 'c {
+
+        let mut view = self.as_view();
+        let mut longest = view.value().map(|value| (0, value));
+
+        for (consumed, key) in path.into_iter().enumerate() {
+            view = match view.descend(key.clone()) {
+                Some(next_view) => next_view,
+                None => break
+            };
+
+            if let Some(value) = view.value() {
+                longest = Some((consumed + 1, value));
+            }
+        }
+
+        longest
+    }
+
+    /// Removes the value at the end of path, returning it.
+    /// Branches left without any children are removed too, back towards the root,
+    /// so that the paths that remain are exactly those leading to values.
+    /// Removing the value of a Trivial MapTrie leaves it empty.
+    pub fn remove<T>(&mut self, path: T) -> Option<M::Value>
+        where
+            T: IntoIterator<Item=M::Key> {
+
+        let mut path = path.into_iter().peekable();
+
+        if let MapTrie::Trivial { .. } = self {
+            if path.peek().is_some() {
+                return None;
+            }
+
+            return match std::mem::replace(self, MapTrie::new()) {
+                MapTrie::Trivial { value } => Some(value),
+                MapTrie::Standard { .. } => None
+            };
+        }
+
+        let map = match self {
+            MapTrie::Standard { map, .. } => map,
+            MapTrie::Trivial { .. } => return None
+        };
+
+        //The edges followed from the root, each of which but the last leads to a Branch
+        let mut edges = Vec::new();
+        let mut node = 0;
+        let mut at_leaf = false;
+
+        for key in path {
+            if at_leaf {
+                return None;
+            }
+
+            let edge = M::edge(node, key);
+
+            match map.node(&edge)? {
+                TrieNode::Branch { id } => node = *id,
+                TrieNode::Leaf { .. } => at_leaf = true
+            }
+
+            edges.push(edge);
+        }
+
+        if !at_leaf {
+            return None;
+        }
+
+        let value = match map.remove_node(&edges.pop()?) {
+            Some(TrieNode::Leaf { value }) => value,
+            _ => return None
+        };
+
+        //Nodes only record the edges into them, so finding children means scanning the map
+        while let Some(edge) = edges.pop() {
+            let has_children = match map.node(&edge) {
+                Some(TrieNode::Branch { id }) => map.edges().any(|(child, _)| M::edge_parts(child).0 == *id),
+                _ => true
+            };
+
+            if has_children {
+                break;
+            }
+
+            map.remove_node(&edge);
+        }
+
+        Some(value)
+    }
+
+    /// Returns the path to and value of every leaf in the MapTrie,
+    /// as found by walking the trie depth first from the root,
+    /// visiting the children of each node in the order the map lists them
+    pub fn entries(&self) -> Vec<(Vec<&M::Key>, &M::Value)> {
+        let map = match self {
+            MapTrie::Trivial { value } => return vec![(Vec::new(), value)],
+            MapTrie::Standard { map, .. } => map
+        };
+
+        let mut children: Children<M::Key, M::Value> = HashMap::new();
+
+        for (edge, node) in map.edges() {
+            let (prev_node, key) = M::edge_parts(edge);
+
+            children.entry(prev_node).or_default().push((key, node));
+        }
+
+        let mut entries = Vec::new();
+        let mut path = Vec::new();
+
+        collect_entries(&children, 0, &mut path, &mut entries);
+
+        entries
+    }
+
+    /// The number of values stored, counted by scanning the nodes
+    pub fn len(&self) -> usize {
+        match self {
+            MapTrie::Trivial { .. } => 1,
+
+            MapTrie::Standard { map, .. } => map.edges()
+                .filter(|(_, node)| matches!(node, TrieNode::Leaf { .. }))
+                .count()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every value, keeping the memory of a Standard MapTrie's map for reuse
+    pub fn clear(&mut self) {
+        match self {
+            MapTrie::Standard { map, next_id } => {
+                map.clear();
+                *next_id = 1;
+            },
+
+            MapTrie::Trivial { .. } => {
+                *self = MapTrie::new();
+            }
+        }
+    }
+
+    /// Iterates over the path to and value of every leaf, as given by entries
+    pub fn iter(&self) -> impl Iterator<Item=(Vec<&M::Key>, &M::Value)> {
+        self.entries().into_iter()
+    }
+
+    /// Iterates over every value, in no particular order.
+    /// Unlike iter this reads the leaves directly, without rebuilding their paths.
+    pub fn values(&self) -> impl Iterator<Item=&M::Value> {
+        let (trivial_value, map) = match self {
+            MapTrie::Trivial { value } => (Some(value), None),
+            MapTrie::Standard { map, .. } => (None, Some(map))
+        };
+
+        let leaf_values = map.into_iter()
+            .flat_map(|map| map.edges())
+            .filter_map(|(_, node)| match node {
+                TrieNode::Leaf { value } => Some(value),
+                TrieNode::Branch { .. } => None
+            });
+
+        trivial_value.into_iter().chain(leaf_values)
+    }
+
+    /// Iterates over the path to every value, as given by entries
+    pub fn keys(&self) -> impl Iterator<Item=Vec<&M::Key>> {
+        self.entries().into_iter().map(|(path, _)| path)
+    }
+
+    /// Renders the MapTrie as a Graphviz DOT graph.
+    /// Branches are drawn as points (with the root as n0),
+    /// leaves as boxes labelled with their values,
+    /// and edges are labelled with their keys.
+    pub fn to_dot<FK, FV>(&self, key_label: FK, value_label: FV) -> String
+        where
+            FK: Fn(&M::Key) -> String,
+            FV: Fn(&M::Value) -> String {
+
+        let mut dot = String::from("digraph trie {\n");
+
+        match self {
+            MapTrie::Trivial { value } => {
+                dot.push_str(&format!("    n0 [shape=box, label=\"{}\"];\n", escape_dot(&value_label(value))));
+            },
+
+            MapTrie::Standard { map, .. } => {
+                dot.push_str("    n0 [shape=point];\n");
+
+                for (leaf_id, (edge, node)) in map.edges().enumerate() {
+                    let target = match node {
+                        TrieNode::Branch { id } => {
+                            dot.push_str(&format!("    n{} [shape=point];\n", id));
+                            format!("n{}", id)
+                        },
+
+                        TrieNode::Leaf { value } => {
+                            dot.push_str(&format!("    l{} [shape=box, label=\"{}\"];\n", leaf_id, escape_dot(&value_label(value))));
+                            format!("l{}", leaf_id)
+                        }
+                    };
+
+                    let (prev_node, key) = M::edge_parts(edge);
+
+                    dot.push_str(&format!("    n{} -> {} [label=\"{}\"];\n", prev_node, target, escape_dot(&key_label(key))));
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+
+        dot
+    }
+}
+
+fn collect_entries<'t, K, V>(
+        children: &Children<'t, K, V>,
+        node: u32,
+        path: &mut Vec<&'t K>,
+        entries: &mut Vec<(Vec<&'t K>, &'t V)>) {
+
+    for (key, child) in children.get(&node).into_iter().flatten() {
+        path.push(*key);
+
+        match child {
+            TrieNode::Branch { id } => collect_entries(children, *id, path, entries),
+            TrieNode::Leaf { value } => entries.push((path.clone(), value))
+        }
+
+        path.pop();
+    }
+}
+
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Inserts each path and value in turn, skipping those that insert rejects
+/// for breaking prefix-freedom, so later values replace earlier ones at the same path
+impl<M, P> FromIterator<(P, M::Value)> for MapTrie<M>
+    where
+        M: EdgeMap,
+        P: IntoIterator<Item=M::Key> {
+
+    fn from_iter<T>(pairs: T) -> Self
+        where
+            T: IntoIterator<Item=(P, M::Value)> {
+
+        let mut map_trie = MapTrie::new();
+
+        for (path, value) in pairs {
+            map_trie.insert(path, value);
+        }
+
+        map_trie
+    }
+}
+
+/// The place for a value at the end of a path, found by MapTrie::entry
+pub enum Entry<'a, M>
+    where
+        M: EdgeMap {
+
+    Occupied(&'a mut M::Value),
+    Vacant(VacantEntry<'a, M>)
+}
+
+/// A path without a value, where one can be inserted without breaking prefix-freedom
+pub struct VacantEntry<'a, M>
+    where
+        M: EdgeMap {
+
+    trie: &'a mut MapTrie<M>,
+
+    /// The first edge missing from the trie, which is only None for the root of an empty trie
+    first_edge: Option<M::Edge>,
+
+    /// The keys of the path after the first missing edge
+    keys: Vec<M::Key>
+}
+
+impl<'a, M> VacantEntry<'a, M>
+    where
+        M: EdgeMap {
+
+    /// Adds the missing part of the path, ending with a Leaf holding value
+    pub fn insert(self, value: M::Value) -> &'a mut M::Value {
+        let VacantEntry { trie, first_edge, keys } = self;
+
+        let mut edge = match first_edge {
+            Some(first_edge) => first_edge,
+
+            None => {
+                *trie = MapTrie::Trivial { value };
+
+                return match trie {
+                    MapTrie::Trivial { value } => value,
+                    MapTrie::Standard { .. } => unreachable!("The trie was just made Trivial")
+                };
+            }
+        };
+
+        let (map, next_id) = match trie {
+            MapTrie::Standard { map, next_id } => (map, next_id),
+            MapTrie::Trivial { .. } => unreachable!("Only Standard tries have vacant paths")
+        };
+
+        //MapTrie::entry checked that there are enough ids for these
+        for key in keys {
+            map.insert_node(edge, TrieNode::Branch { id: *next_id });
+            edge = M::edge(*next_id, key);
+            *next_id += 1;
+        }
+
+        map.insert_node(edge.clone(), TrieNode::Leaf { value });
+
+        match map.node_mut(&edge) {
+            Some(TrieNode::Leaf { value }) => value,
+            _ => unreachable!("The Leaf was just inserted")
+        }
+    }
+}
+
+impl<'a, M> Trie<'a, M::Key, M::Value> for &'a MapTrie<M>
+    where
+        M: EdgeMap,
+        M::Value: 'a {
+
+    type View = MapTrieView<'a, M>;
+
+    fn as_view(self) -> MapTrieView<'a, M> {
+        MapTrieView { trie: self, edge: None }
+    }
+}
+
+impl<'a, M> Trie<'a, M::Key, M::Value> for &'a mut MapTrie<M>
+    where
+        M: EdgeMap,
+        M::Value: 'a {
+
+    type View = MapTrieView<'a, M>;
+
+    fn as_view(self) -> MapTrieView<'a, M> {
+        MapTrieView { trie: self, edge: None }
+    }
+}
+
+impl<'a, M> TrieMut<'a, M::Key, M::Value> for &'a mut MapTrie<M>
+    where
+        M: EdgeMap,
+        M::Value: 'a {
+
+    type ViewMut = MapTrieViewMut<'a, M>;
+
+    fn as_view_mut(self) -> MapTrieViewMut<'a, M> {
+        MapTrieViewMut { trie: self, edge: None }
+    }
+
+    /// Goes through entry rather than descend_or_add, so that a path needing
+    /// more node ids than remain adds nothing, instead of leaving its first Branches behind
+    fn insert<T>(self, path: T, new_val: M::Value) -> bool
+        where
+            T: IntoIterator<Item=M::Key> {
+
+        match self.entry(path) {
+            Some(Entry::Occupied(value)) => {
+                *value = new_val;
+                true
+            },
+
+            Some(Entry::Vacant(vacant)) => {
+                vacant.insert(new_val);
+                true
+            },
+
+            None => false
+        }
+    }
+}
+
+/// A read-only view of a MapTrie
+pub struct MapTrieView<'a, M>
+    where
+        M: EdgeMap {
+
+    /// The MapTrie being Viewed
+    trie: &'a MapTrie<M>,
+
+    /// The Some edge leading to the current node,
+    /// or None if the current node is the root
+    edge: Option<M::Edge>
+}
+
+impl<'a, M> TrieView<'a, M::Key, M::Value> for MapTrieView<'a, M>
+    where
+        M: EdgeMap,
+        M::Value: 'a {
+
+    fn value(&self) -> Option<&'a M::Value> {
+        match (self.trie, &self.edge) {
+            (MapTrie::Trivial { value }, None) => Some(value),
+
+            (MapTrie::Standard { map, .. }, Some(edge)) => match map.node(edge) {
+                Some(TrieNode::Leaf { value }) => Some(value),
+                _ => None
+            },
+
+            _ => None
+        }
+    }
+
+    fn descend(&self, key: M::Key) -> Option<Self> {
+        let map = match self.trie {
+            MapTrie::Standard { map, .. } => map,
+            MapTrie::Trivial { .. } => return None
+        };
+
+        let prev_node = match &self.edge {
+            None => 0,
+
+            Some(edge) => match map.node(edge)? {
+                TrieNode::Branch { id } => *id,
+                TrieNode::Leaf { .. } => return None
+            }
+        };
+
+        let next_edge = M::edge(prev_node, key);
+
+        //Only descend to nodes that exist, so that views are never dead ends
+        map.node(&next_edge)?;
+
+        Some(MapTrieView { trie: self.trie, edge: Some(next_edge) })
+    }
+}
+
+/// A mutable view of a MapTrie
+pub struct MapTrieViewMut<'a, M>
+    where
+        M: EdgeMap {
+
+    /// The MapTrie being Viewed
+    trie: &'a mut MapTrie<M>,
+
+    /// The Some edge leading to the current node,
+    /// or None if the current node is the root
+    edge: Option<M::Edge>
+}
+
+impl<'a, M> TrieViewMut<M::Key, M::Value> for MapTrieViewMut<'a, M>
+    where
+        M: EdgeMap {
+
+    fn value(&mut self) -> Option<&mut M::Value> {
+        match (&mut *self.trie, &self.edge) {
+            (MapTrie::Trivial { value }, None) => Some(value),
+
+            (MapTrie::Standard { map, .. }, Some(edge)) => match map.node_mut(edge) {
+                Some(TrieNode::Leaf { value }) => Some(value),
+                _ => None
+            },
+
+            _ => None
+        }
+    }
+
+    fn set_value(&mut self, new_value: M::Value) -> bool {
+        match (&mut *self.trie, &self.edge) {
+            (MapTrie::Trivial { value }, None) => {
+                *value = new_value;
+                true
+            },
+
+            //Only the root of an empty trie may take a value
+            (MapTrie::Standard { map, .. }, None) => {
+                if !map.is_empty() {
+                    return false;
+                }
+
+                *self.trie = MapTrie::Trivial { value: new_value };
+                true
+            },
+
+            (MapTrie::Standard { map, .. }, Some(edge)) => match map.node_mut(edge) {
+                None => {
+                    map.insert_node(edge.clone(), TrieNode::Leaf { value: new_value });
+                    true
+                },
+
+                Some(TrieNode::Leaf { value }) => {
+                    *value = new_value;
+                    true
+                },
+
+                Some(TrieNode::Branch { .. }) => false
+            },
+
+            _ => false
+        }
+    }
+
+    fn descend(self, key: M::Key) -> Option<Self> {
+        let prev_node = match (&*self.trie, &self.edge) {
+            (MapTrie::Standard { .. }, None) => 0,
+
+            (MapTrie::Standard { map, .. }, Some(edge)) => match map.node(edge)? {
+                TrieNode::Branch { id } => *id,
+                TrieNode::Leaf { .. } => return None
+            },
+
+            _ => return None
+        };
+
+        Some(MapTrieViewMut { trie: self.trie, edge: Some(M::edge(prev_node, key)) })
+    }
+
+    fn descend_or_add(self, key: M::Key) -> Option<Self> {
+        let prev_node = match (&mut *self.trie, &self.edge) {
+            (MapTrie::Standard { .. }, None) => 0,
+
+            (MapTrie::Standard { map, next_id }, Some(edge)) => match map.node(edge) {
+                Some(TrieNode::Branch { id }) => *id,
+
+                //Adding a child to a Leaf would violate the prefix-free property
+                Some(TrieNode::Leaf { .. }) => return None,
+
+                None => {
+                    //Running out of ids fails the descent, rather than reusing one
+                    let id = *next_id;
+                    *next_id = id.checked_add(1)?;
+
+                    map.insert_node(edge.clone(), TrieNode::Branch { id });
+
+                    id
+                }
+            },
+
+            _ => return None
+        };
+
+        Some(MapTrieViewMut { trie: self.trie, edge: Some(M::edge(prev_node, key)) })
+    }
+}
@@ -1,15 +1,24 @@
+pub mod btree;
 pub mod hash;
+pub mod map;
+pub mod overlapping;
+
+use std::collections::HashMap;
+use std::hash::Hash;
 
 /// The Trie trait represents a read-only mapping from
 /// a sequence of key elements to a single value.
 /// This allows for get() map style behavior.
 /// All Trie implementations must be prefix free.
-pub trait Trie<K, V>: Sized {
+pub trait Trie<'a, K, V: 'a>: Sized {
 
-    type View: TrieView<K, V>;
+    type View: TrieView<'a, K, V>;
 
     fn as_view(self) -> Self::View;
     
+    /// Returns a view of the node at the end of path, if it exists.
+    /// The empty path gives a view of the root,
+    /// so a value stored at the root is retrieved like any other.
     fn get<T>(self, path: T) -> Option<Self::View>
         where
             T: IntoIterator<Item=K> {
@@ -33,8 +42,8 @@ pub trait Trie<K, V>: Sized {
 /// of a Trie node.
 /// In order to enforce prefix-free behavior a given
 /// TrieView must never have a value and children
-pub trait TrieView<K, V>: Sized {
-    fn value(&self) -> Option<&V>;
+pub trait TrieView<'a, K, V: 'a>: Sized {
+    fn value(&self) -> Option<&'a V>;
 
     fn descend(&self, key: K) -> Option<Self>;
 }
@@ -43,7 +52,7 @@ pub trait TrieView<K, V>: Sized {
 /// a sequence of key elements to a single value.
 /// This allows for both get() and insert() map style behavior
 /// All TrieMut implementations must be prefix free.
-pub trait TrieMut<K, V>: Trie<K, V> {
+pub trait TrieMut<'a, K, V: 'a>: Trie<'a, K, V> {
 
     type ViewMut: TrieViewMut<K, V>;
 
@@ -86,3 +95,66 @@ pub trait TrieViewMut<K, V>: Sized {
     
     fn descend_or_add(self, key: K) -> Option<Self>;
 }
+
+/// Checks that no path in paths is a prefix of another,
+/// (including two paths being equal), which is the property
+/// every Trie relies on.
+/// On failure the indices of a conflicting pair are returned,
+/// with the prefix first.
+pub fn is_prefix_free<K>(paths: &[Vec<K>]) -> Result<(), (usize, usize)>
+    where
+        K: Hash + Eq {
+
+    let mut indices: HashMap<&[K], usize> = HashMap::new();
+
+    for (index, path) in paths.iter().enumerate() {
+        if let Some(&existing) = indices.get(path.as_slice()) {
+            return Err((existing, index));
+        }
+
+        indices.insert(path, index);
+    }
+
+    for (index, path) in paths.iter().enumerate() {
+        for prefix_len in 0 .. path.len() {
+            if let Some(&prefix_index) = indices.get(&path[ .. prefix_len]) {
+                return Err((prefix_index, index));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefix_free_paths() {
+        let paths = vec![
+            vec!["A", "B"],
+            vec!["A", "C"],
+            vec!["B"]
+        ];
+
+        assert_eq!(is_prefix_free(&paths), Ok(()));
+    }
+
+    #[test]
+    fn prefixed_paths() {
+        let paths = vec![
+            vec!["A", "B", "C"],
+            vec!["B"],
+            vec!["A", "B"]
+        ];
+
+        assert_eq!(is_prefix_free(&paths), Err((2, 0)));
+    }
+
+    #[test]
+    fn duplicate_and_empty_paths() {
+        assert_eq!(is_prefix_free(&[vec![1, 2], vec![1, 2]]), Err((0, 1)));
+        assert_eq!(is_prefix_free(&[vec![1, 2], vec![]]), Err((1, 0)));
+    }
+}
@@ -0,0 +1,159 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A trie that relaxes the prefix-free property, so that a node may hold a value
+/// and also have children, e.g. both `[a]` and `[a, b]` may hold values.
+/// Lookups prefer the longest path holding a value.
+///
+/// Since it is not prefix free it does not implement Trie or TrieMut.
+pub struct OverlappingTrie<K, V>
+    where
+        K: Hash + Eq {
+
+    /// Nodes are represented by their index, with the root being zero
+    nodes: Vec<OverlappingTrieNode<K, V>>
+}
+
+struct OverlappingTrieNode<K, V>
+    where
+        K: Hash + Eq {
+
+    value: Option<V>,
+    children: HashMap<K, usize>
+}
+
+impl<K, V> OverlappingTrieNode<K, V>
+    where
+        K: Hash + Eq {
+
+    fn new() -> Self {
+        OverlappingTrieNode {
+            value: None,
+            children: HashMap::new()
+        }
+    }
+}
+
+impl<K, V> OverlappingTrie<K, V>
+    where
+        K: Hash + Eq {
+
+    /// Constructs an empty OverlappingTrie
+    pub fn new() -> Self {
+        OverlappingTrie {
+            nodes: vec![OverlappingTrieNode::new()]
+        }
+    }
+
+    /// Stores new_val at the end of path, returning the value it replaces
+    pub fn insert<T>(&mut self, path: T, new_val: V) -> Option<V>
+        where
+            T: IntoIterator<Item=K> {
+
+        let mut node = 0;
+
+        for key in path {
+            let next_id = self.nodes.len();
+
+            node = match self.nodes[node].children.get(&key) {
+                Some(&child) => child,
+                None => {
+                    self.nodes[node].children.insert(key, next_id);
+                    self.nodes.push(OverlappingTrieNode::new());
+
+                    next_id
+                }
+            };
+        }
+
+        self.nodes[node].value.replace(new_val)
+    }
+
+    /// Removes the value stored at exactly the end of path, returning it.
+    /// The nodes leading to it are kept, so that inserting there again reuses them.
+    pub fn remove<T>(&mut self, path: T) -> Option<V>
+        where
+            T: IntoIterator<Item=K> {
+
+        let mut node = 0;
+
+        for key in path {
+            node = *self.nodes[node].children.get(&key)?;
+        }
+
+        self.nodes[node].value.take()
+    }
+
+    /// Walks as far along keys as the trie allows, returning the value of the
+    /// deepest node passed that holds one, and the number of keys leading to it.
+    /// Keys are borrowed, so that e.g. a trie over Strings is walked with &strs.
+    pub fn longest_match<'q, Q, T>(&self, keys: T) -> Option<(usize, &V)>
+        where
+            T: IntoIterator<Item=&'q Q>,
+            K: Borrow<Q>,
+            Q: Hash + Eq + ?Sized + 'q {
+
+        let mut node = 0;
+        let mut longest = self.nodes[0].value.as_ref().map(|value| (0, value));
+
+        for (position, key) in keys.into_iter().enumerate() {
+            node = match self.nodes[node].children.get(key) {
+                Some(&child) => child,
+                None => break
+            };
+
+            if let Some(value) = &self.nodes[node].value {
+                longest = Some((position + 1, value));
+            }
+        }
+
+        longest
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stores_overlapping_paths() {
+        let mut trie = OverlappingTrie::new();
+
+        assert_eq!(trie.insert(vec!["a"], 1), None);
+        assert_eq!(trie.insert(vec!["a", "b"], 2), None);
+        assert_eq!(trie.insert(vec!["a"], 3), Some(1));
+
+        assert_eq!(trie.longest_match(&["a"]), Some((1, &3)));
+        assert_eq!(trie.longest_match(&["a", "b"]), Some((2, &2)));
+        assert_eq!(trie.longest_match(&["b"]), None);
+
+        assert_eq!(trie.remove(vec!["a"]), Some(3));
+        assert_eq!(trie.longest_match(&["a"]), None);
+        assert_eq!(trie.longest_match(&["a", "b"]), Some((2, &2)));
+        assert_eq!(trie.remove(vec!["a"]), None);
+    }
+
+    #[test]
+    fn prefers_longest_match() {
+        let mut trie = OverlappingTrie::new();
+
+        trie.insert(vec!["-", ">"], "arrow");
+        trie.insert(vec!["-", ">", ">"], "double arrow");
+        trie.insert(vec!["-"], "minus");
+
+        assert_eq!(trie.longest_match(&["-", ">", ">", "x"]), Some((3, &"double arrow")));
+        assert_eq!(trie.longest_match(&["-", ">", "x"]), Some((2, &"arrow")));
+        assert_eq!(trie.longest_match(&["-", "x"]), Some((1, &"minus")));
+        assert_eq!(trie.longest_match(&["x"]), None);
+    }
+
+    #[test]
+    fn matches_borrowed_keys() {
+        let mut trie = OverlappingTrie::new();
+
+        trie.insert(vec!["a".to_string(), "b".to_string()], 1);
+
+        assert_eq!(trie.longest_match(["a", "b"].iter().copied()), Some((2, &1)));
+    }
+}